@@ -0,0 +1,170 @@
+// The bench subcommand.
+//
+// Runs the game headless for --frames frames, fed a replay when one is given
+// and no input at all otherwise, and reports percentiles of the time the
+// game's update_and_render took per frame. The first --warmup frames aren't
+// counted. With --baseline the result is compared against an earlier one
+// saved with --save-baseline, and any percentile more than --threshold
+// percent slower than the baseline fails the run.
+use anyhow::Context;
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::frame_stats::{percentile, summarize};
+use crate::profiler::FrameProfile;
+
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    // Replay to feed the game, see --record
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+    #[arg(long, default_value = "600")]
+    frames: usize,
+    #[arg(long, default_value = "60")]
+    warmup: usize,
+    // Result to compare against
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+    // Write the result here, to compare later runs against
+    #[arg(long)]
+    save_baseline: Option<PathBuf>,
+    // Percent a percentile may be slower than the baseline
+    #[arg(long, default_value = "10")]
+    threshold: f32,
+}
+
+// Milliseconds per frame spent in update_and_render
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub struct BenchResult {
+    pub frames: usize,
+    pub min: f32,
+    pub average: f32,
+    pub p50: f32,
+    pub p90: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+pub struct Bench {
+    args: BenchArgs,
+    frame: usize,
+    update_times: Vec<f32>,
+}
+
+impl Bench {
+    pub fn new(args: BenchArgs) -> Self {
+        Self {
+            args,
+            frame: 0,
+            update_times: vec![],
+        }
+    }
+
+    // Call once per frame with what the profiler made of it
+    pub fn record(&mut self, frame_profile: &FrameProfile) {
+        self.frame += 1;
+        if self.frame <= self.args.warmup {
+            return;
+        }
+        let update_time = frame_profile
+            .blocks
+            .iter()
+            .filter(|block| block.name == "game")
+            .map(|block| block.milliseconds)
+            .sum();
+        self.update_times.push(update_time);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.update_times.len() >= self.args.frames
+    }
+
+    fn result(&self) -> BenchResult {
+        let summary = summarize(self.update_times.iter().copied());
+        let mut sorted = self.update_times.clone();
+        sorted.sort_by(f32::total_cmp);
+        if sorted.is_empty() {
+            return BenchResult::default();
+        }
+        BenchResult {
+            frames: sorted.len(),
+            min: summary.min,
+            average: summary.average,
+            p50: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p99: summary.p99,
+            max: summary.max,
+        }
+    }
+
+    // Prints the result, saves and compares it as asked. Returns false when
+    // the run regressed or couldn't be compared.
+    pub fn report(&self) -> bool {
+        let result = self.result();
+        println!("update_and_render over {} frames:", result.frames);
+        println!("  {:<8} {:>8.3}ms", "min", result.min);
+        println!("  {:<8} {:>8.3}ms", "max", result.max);
+        for (name, milliseconds) in percentiles(&result) {
+            println!("  {:<8} {:>8.3}ms", name, milliseconds);
+        }
+        if let Some(path) = self.args.save_baseline.as_ref() {
+            match save(path, &result) {
+                Ok(()) => println!("Saved baseline to {}", path.display()),
+                Err(error) => println!("Failed to save baseline to {}: {:?}", path.display(), error),
+            }
+        }
+        let Some(path) = self.args.baseline.as_ref() else {
+            return true;
+        };
+        let baseline = match load(path) {
+            Ok(baseline) => baseline,
+            Err(error) => {
+                println!("Failed to read baseline {}: {:?}", path.display(), error);
+                return false;
+            }
+        };
+        let mut passed = true;
+        println!("Against {} (threshold {}%):", path.display(), self.args.threshold);
+        for ((name, milliseconds), (_, baseline_milliseconds)) in
+            percentiles(&result).into_iter().zip(percentiles(&baseline))
+        {
+            let change = if baseline_milliseconds > 0.0 {
+                100.0 * (milliseconds - baseline_milliseconds) / baseline_milliseconds
+            } else {
+                0.0
+            };
+            let regressed = change > self.args.threshold;
+            passed &= !regressed;
+            println!(
+                "  {:<8} {:>8.3}ms vs {:>8.3}ms  {:+.1}%{}",
+                name,
+                milliseconds,
+                baseline_milliseconds,
+                change,
+                if regressed { "  REGRESSED" } else { "" }
+            );
+        }
+        passed
+    }
+}
+
+// The ones compared against the baseline. min and max are too noisy.
+fn percentiles(result: &BenchResult) -> [(&'static str, f32); 4] {
+    [
+        ("average", result.average),
+        ("p50", result.p50),
+        ("p90", result.p90),
+        ("p99", result.p99),
+    ]
+}
+
+fn save(path: &Path, result: &BenchResult) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(result)?)?;
+    Ok(())
+}
+
+fn load(path: &Path) -> anyhow::Result<BenchResult> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    Ok(serde_json::from_str(&json)?)
+}
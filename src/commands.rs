@@ -0,0 +1,455 @@
+// Commands from the editor to the game loop, and what the editor gets back.
+//
+// The editor never locks GAME_STATE itself, it pushes EditorCommands into
+// EDITOR_COMMANDS and the main loop applies them in one go right after the
+// platform events, so nothing changes under the game mid-frame. After the
+// game has updated, the host publishes an EditorSnapshot, a copy of the state
+// the editor shows until the next one comes in.
+//
+// Edits to entities, the zmap and tweakables go into the undo history, see
+// history.rs.
+// Entities are created and deleted in entities.rs. Nothing can be edited
+// while recording or playing back.
+use anyhow::{anyhow, bail};
+use engine::{access_global, edit_global, GameState};
+use serde::{de::DeserializeOwned, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::entities;
+use crate::history::{self, EditTarget, History, HistorySummary};
+use crate::tweaks::{self, TweakValue};
+use crate::zmap::{self, LayerVisibility};
+use crate::{overlay, recording, scene, Platform, EDITOR_COMMANDS, EDITOR_SNAPSHOT, GAME_INPUT, GAME_STATE};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditorCommand {
+    // Edits in a row with the same merge key undo as one
+    SetPosition {
+        entity: usize,
+        position: [f32; 3],
+        merge_key: Option<u64>,
+    },
+    // Sets the field at a JSON pointer into the serialized entity, like
+    // "/position/x"
+    SetField {
+        entity: usize,
+        pointer: String,
+        value: serde_json::Value,
+        merge_key: Option<u64>,
+    },
+    // Moves a zmap member, as it serializes, to layer before the member at
+    // index, or to the end
+    MoveToLayer {
+        member: serde_json::Value,
+        layer: String,
+        index: Option<usize>,
+    },
+    SetLayerHidden {
+        layer: String,
+        hidden: bool,
+    },
+    SetLayerSolo(Option<String>),
+    // Adds an entity from a template to the end of the entities, on layer
+    CreateEntity {
+        template: String,
+        layer: Option<String>,
+    },
+    // Adds a copy of entity moved by offset to the end of the entities
+    DuplicateEntity {
+        entity: usize,
+        offset: [f32; 3],
+    },
+    // Moves every entity after it down by one
+    DeleteEntity(usize),
+    SaveTemplate {
+        entity: usize,
+        name: String,
+    },
+    // Clamped to the tweakable's range
+    SetTweak {
+        name: String,
+        value: TweakValue,
+        merge_key: Option<u64>,
+    },
+    // Writes the tweakables to the --tuning file
+    SaveTweaks,
+    Undo,
+    Redo,
+    SetPaused(bool),
+    // Reload the game library even if it hasn't changed
+    Reload,
+    StartRecording,
+    // Stops a recording and loops it, or stops a loop
+    StopRecording,
+    SaveScene(PathBuf),
+    // Replaces the entities, zmap and texts and clears the undo history
+    LoadScene(PathBuf),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EditorSnapshot {
+    pub frame: usize,
+    pub game_state: GameState,
+    pub paused: bool,
+    pub recording: bool,
+    pub playing_back: bool,
+    pub history: HistorySummary,
+    pub layer_visibility: LayerVisibility,
+    // The scene last saved or loaded
+    pub scene_path: Option<PathBuf>,
+    // Edited since the scene was saved or loaded
+    pub scene_unsaved: bool,
+    pub templates: Vec<String>,
+}
+
+pub fn send(command: EditorCommand) {
+    edit_global!(commands, EDITOR_COMMANDS, {
+        commands.push(command);
+    });
+}
+
+// Call once per frame, right after the platform events are handled
+pub fn apply(platform: &mut Platform) {
+    let mut commands = vec![];
+    edit_global!(editor_commands, EDITOR_COMMANDS, {
+        commands.append(&mut editor_commands);
+    });
+    for command in commands {
+        if let Err(error) = apply_command(platform, &command) {
+            log_warn!("editor", "Editor command {:?} failed: {:?}", command, error);
+        }
+    }
+}
+
+pub fn apply_command(platform: &mut Platform, command: &EditorCommand) -> anyhow::Result<()> {
+    match command {
+        EditorCommand::SetPosition {
+            entity,
+            position,
+            merge_key,
+        } => {
+            refuse_while_recording(platform)?;
+            let mut result = Err(anyhow!("there is no entity {}", entity));
+            edit_global!(game_state, GAME_STATE, {
+                if let Some(entity) = game_state.entities.get_mut(*entity) {
+                    result = history::record_edit(entity, "/position", |entity| {
+                        entity.position.x = position[0];
+                        entity.position.y = position[1];
+                        entity.position.z = position[2];
+                        Ok(())
+                    });
+                }
+            });
+            let (before, after) = result?;
+            let description = format!("Move entity {}", entity);
+            let target = EditTarget::Field {
+                entity: *entity,
+                pointer: "/position".to_string(),
+            };
+            platform.history.push(description, target, before, after, *merge_key);
+        }
+        EditorCommand::SetField {
+            entity,
+            pointer,
+            value,
+            merge_key,
+        } => {
+            refuse_while_recording(platform)?;
+            let mut result = Err(anyhow!("there is no entity {}", entity));
+            edit_global!(game_state, GAME_STATE, {
+                if let Some(entity) = game_state.entities.get_mut(*entity) {
+                    result = history::record_edit(entity, pointer, |entity| set_field(entity, pointer, value));
+                }
+            });
+            let (before, after) = result?;
+            let description = match pointer.as_str() {
+                "" => format!("Set entity {}", entity),
+                pointer => format!("Set {} of entity {}", pointer, entity),
+            };
+            let target = EditTarget::Field {
+                entity: *entity,
+                pointer: pointer.clone(),
+            };
+            platform.history.push(description, target, before, after, *merge_key);
+        }
+        EditorCommand::MoveToLayer { member, layer, index } => {
+            refuse_while_recording(platform)?;
+            let mut result = Err(anyhow!("couldn't edit the game state"));
+            edit_global!(game_state, GAME_STATE, {
+                result = zmap::edit_layers(&mut game_state.zmap, |layers| {
+                    let before = zmap::find_member(layers, member)?;
+                    zmap::move_member(layers, member, layer, *index)?;
+                    Ok((before, zmap::find_member(layers, member)?))
+                });
+            });
+            let (before, after) = result?;
+            let description = format!("Move {} to layer {}", member, layer);
+            let target = EditTarget::Move(member.clone());
+            platform.history.push(
+                description,
+                target,
+                serde_json::to_value(before)?,
+                serde_json::to_value(after)?,
+                None,
+            );
+        }
+        EditorCommand::SetLayerHidden { layer, hidden } => {
+            if *hidden {
+                platform.layer_visibility.hidden.insert(layer.clone());
+            } else {
+                platform.layer_visibility.hidden.remove(layer);
+            }
+            show_layer_visibility(&platform.layer_visibility);
+        }
+        EditorCommand::SetLayerSolo(layer) => {
+            platform.layer_visibility.solo = layer.clone();
+            show_layer_visibility(&platform.layer_visibility);
+        }
+        EditorCommand::CreateEntity { template, layer } => {
+            refuse_while_recording(platform)?;
+            let Some(template_entity) = platform.templates.get(template) else {
+                bail!("there is no template {}", template);
+            };
+            let mut result = Err(anyhow!("couldn't edit the game state"));
+            edit_global!(game_state, GAME_STATE, {
+                let game_state: &mut GameState = &mut *game_state;
+                result = entities::create(&mut game_state.entities, &mut game_state.zmap, template_entity, layer.as_deref())
+                    .and_then(|index| Ok((index, entities::capture(&game_state.entities, &game_state.zmap, index)?)));
+            });
+            let (index, after) = result?;
+            let description = format!("Create entity {} from {}", index, template);
+            let target = EditTarget::AddedOrRemoved(index);
+            platform.history.push(description, target, serde_json::Value::Null, after, None);
+        }
+        EditorCommand::DuplicateEntity { entity, offset } => {
+            refuse_while_recording(platform)?;
+            let mut result = Err(anyhow!("couldn't edit the game state"));
+            edit_global!(game_state, GAME_STATE, {
+                let game_state: &mut GameState = &mut *game_state;
+                result = entities::duplicate(&mut game_state.entities, &mut game_state.zmap, *entity, *offset)
+                    .and_then(|index| Ok((index, entities::capture(&game_state.entities, &game_state.zmap, index)?)));
+            });
+            let (index, after) = result?;
+            let description = format!("Duplicate entity {} as {}", entity, index);
+            let target = EditTarget::AddedOrRemoved(index);
+            platform.history.push(description, target, serde_json::Value::Null, after, None);
+        }
+        EditorCommand::DeleteEntity(entity) => {
+            refuse_while_recording(platform)?;
+            let mut result = Err(anyhow!("couldn't edit the game state"));
+            edit_global!(game_state, GAME_STATE, {
+                let game_state: &mut GameState = &mut *game_state;
+                result = entities::capture(&game_state.entities, &game_state.zmap, *entity).and_then(|before| {
+                    Ok((before, entities::delete(&mut game_state.entities, &mut game_state.zmap, *entity)?))
+                });
+            });
+            let (before, remap) = result?;
+            entities::send_remap(platform.remap_entities, &remap);
+            let description = format!("Delete entity {}", entity);
+            let target = EditTarget::AddedOrRemoved(*entity);
+            platform.history.push(description, target, before, serde_json::Value::Null, None);
+        }
+        EditorCommand::SaveTemplate { entity, name } => {
+            let mut template = Err(anyhow!("there is no entity {}", entity));
+            access_global!(game_state, GAME_STATE, {
+                if let Some(entity) = game_state.entities.get(*entity) {
+                    template = serde_json::to_value(entity).map_err(anyhow::Error::from);
+                }
+            });
+            platform.templates.insert(name.clone(), template?);
+            entities::save_templates(&platform.cli.templates, &platform.templates)?;
+            log_info!("entities", "Saved entity {} as template {}", entity, name);
+        }
+        EditorCommand::SetTweak { name, value, merge_key } => {
+            refuse_while_recording(platform)?;
+            let (before, after) = tweaks::set(name, *value)?;
+            let description = format!("Tweak {}", name);
+            let target = EditTarget::Tweak(name.clone());
+            platform.history.push(
+                description,
+                target,
+                serde_json::to_value(before)?,
+                serde_json::to_value(after)?,
+                *merge_key,
+            );
+        }
+        EditorCommand::SaveTweaks => tweaks::save()?,
+        EditorCommand::Undo => {
+            refuse_while_recording(platform)?;
+            let remap = platform.history.undo(|entry| restore(&entry.target, &entry.before))?;
+            entities::send_remap(platform.remap_entities, &remap);
+        }
+        EditorCommand::Redo => {
+            refuse_while_recording(platform)?;
+            let remap = platform.history.redo(|entry| restore(&entry.target, &entry.after))?;
+            entities::send_remap(platform.remap_entities, &remap);
+        }
+        EditorCommand::SetPaused(paused) => {
+            if *paused && recording::is_recording() {
+                bail!("can't pause while recording");
+            }
+            platform.paused = *paused;
+        }
+        EditorCommand::Reload => platform.reload_requested = true,
+        EditorCommand::StartRecording => recording::record(platform)?,
+        EditorCommand::StopRecording => recording::stop_or_play(platform),
+        EditorCommand::SaveScene(path) => {
+            scene::save(path)?;
+            log_info!("scene", "Saved scene to {}", path.display());
+            platform.scene_path = Some(path.clone());
+            platform.saved_position = platform.history.position();
+        }
+        EditorCommand::LoadScene(path) => {
+            if recording::is_recording() {
+                bail!("can't load a scene while recording");
+            }
+            let remap = scene::load(path)?;
+            entities::send_remap(platform.remap_entities, &remap);
+            log_info!("scene", "Loaded scene from {}", path.display());
+            // A loop would put its own state back over the scene, and so
+            // would rewind with the frame it's on
+            platform.playback = None;
+            platform.rewind.stop_rewinding();
+            // The entries are edits to what was there before
+            platform.history = History::default();
+            platform.scene_path = Some(path.clone());
+            platform.saved_position = platform.history.position();
+        }
+    }
+    Ok(())
+}
+
+// Edits aren't part of a recording, a loop would put its own state back over
+// them, or go on from them and desync
+fn refuse_while_recording(platform: &Platform) -> anyhow::Result<()> {
+    if recording::is_recording() {
+        bail!("can't edit while recording");
+    }
+    if platform.playback.is_some() {
+        bail!("can't edit while playing back");
+    }
+    Ok(())
+}
+
+// Goes through serde, so any field the game serializes can be set
+fn set_field<T: Serialize + DeserializeOwned>(
+    target: &mut T,
+    pointer: &str,
+    value: &serde_json::Value,
+) -> anyhow::Result<()> {
+    let mut fields = serde_json::to_value(&*target)?;
+    let Some(field) = fields.pointer_mut(pointer) else {
+        bail!("no field at {}", pointer);
+    };
+    *field = value.clone();
+    *target = serde_json::from_value(fields)?;
+    Ok(())
+}
+
+// Returns the remap for the game when entities moved, see entities.rs
+fn restore(target: &EditTarget, value: &serde_json::Value) -> anyhow::Result<Vec<usize>> {
+    let mut result = Ok(vec![]);
+    edit_global!(game_state, GAME_STATE, {
+        let game_state: &mut GameState = &mut *game_state;
+        result = match target {
+            EditTarget::Field { entity, pointer } => match game_state.entities.get_mut(*entity) {
+                Some(entity) => set_field(entity, pointer, value).map(|()| vec![]),
+                None => Err(anyhow!("there is no entity {}", entity)),
+            },
+            EditTarget::Move(member) => serde_json::from_value(value.clone())
+                .map_err(anyhow::Error::from)
+                .and_then(|position| {
+                    zmap::edit_layers(&mut game_state.zmap, |layers| zmap::place_member(layers, member, &position))
+                })
+                .map(|()| vec![]),
+            EditTarget::AddedOrRemoved(index) if value.is_null() => {
+                entities::delete(&mut game_state.entities, &mut game_state.zmap, *index)
+            }
+            EditTarget::AddedOrRemoved(index) => {
+                entities::insert(&mut game_state.entities, &mut game_state.zmap, *index, value)
+            }
+            EditTarget::Tweak(name) => serde_json::from_value(value.clone())
+                .map_err(anyhow::Error::from)
+                .and_then(|value| tweaks::set(name, value))
+                .map(|_| vec![]),
+        };
+    });
+    // Undoing a tweak sticks like tweaking does
+    if result.is_ok() && matches!(target, EditTarget::Tweak(_)) {
+        tweaks::save()?;
+    }
+    result
+}
+
+// Passes it on to the game, and says so on the overlay, so nobody wonders why
+// things are missing from the game window
+fn show_layer_visibility(visibility: &LayerVisibility) {
+    zmap::set_visibility(visibility);
+    if let Some(solo) = visibility.solo.as_ref() {
+        overlay::set_line("host", "z-layers", format!("solo {}", solo));
+    } else if !visibility.hidden.is_empty() {
+        let hidden: Vec<&str> = visibility.hidden.iter().map(String::as_str).collect();
+        overlay::set_line("host", "z-layers", format!("hiding {}", hidden.join(", ")));
+    } else {
+        overlay::remove_line("host", "z-layers");
+    }
+}
+
+pub fn set_from_json<T: DeserializeOwned>(target: &mut T, value: &serde_json::Value) -> anyhow::Result<()> {
+    *target = serde_json::from_value(value.clone())?;
+    Ok(())
+}
+
+// Call once per frame right before the game updates. A paused game gets no
+// input and no time passes for it, so it keeps rendering the same frame,
+// unless it's being stepped. Returns whether it froze the game this frame.
+pub fn freeze_if_paused(platform: &mut Platform) -> bool {
+    if !is_frozen(platform) {
+        platform.step_frames = platform.step_frames.saturating_sub(1);
+        return false;
+    }
+    edit_global!(game_state, GAME_STATE, {
+        game_state.timing_info.elapsed = 0.0;
+    });
+    edit_global!(game_input, GAME_INPUT, {
+        game_input.clear();
+    });
+    true
+}
+
+// Paused and not being stepped this frame
+pub fn is_frozen(platform: &Platform) -> bool {
+    platform.paused && platform.step_frames == 0
+}
+
+// Call once per frame after the game updates
+pub fn publish_snapshot(platform: &Platform, frame: usize) {
+    let mut snapshot = EditorSnapshot {
+        frame,
+        paused: platform.paused,
+        recording: recording::is_recording(),
+        playing_back: platform.playback.is_some(),
+        history: platform.history.summary(),
+        layer_visibility: platform.layer_visibility.clone(),
+        scene_path: platform.scene_path.clone(),
+        scene_unsaved: platform.history.position() != platform.saved_position,
+        templates: platform.templates.keys().cloned().collect(),
+        ..Default::default()
+    };
+    access_global!(game_state, GAME_STATE, {
+        snapshot.game_state = game_state.clone();
+    });
+    edit_global!(editor_snapshot, EDITOR_SNAPSHOT, {
+        *editor_snapshot = Arc::new(snapshot);
+    });
+}
+
+// The newest snapshot, cheap to call every editor frame
+pub fn snapshot() -> Arc<EditorSnapshot> {
+    let mut snapshot = Arc::default();
+    access_global!(editor_snapshot, EDITOR_SNAPSHOT, {
+        snapshot = editor_snapshot.clone();
+    });
+    snapshot
+}
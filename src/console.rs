@@ -0,0 +1,405 @@
+// The developer console, typed commands from the editor.
+//
+// The editor submits lines with submit, and the main loop runs them with
+// handle_requests right after the editor commands, so a command can do
+// anything the host can between frames. Output goes to the console's own
+// transcript, which the editor shows above the command line.
+//
+// The game adds its own commands at init through the ConsoleApi function
+// table in GameMemory, with a name and a line of help. The host runs them by
+// calling the game library's console_command export with the name and the
+// rest of the line. Going through the export rather than a function pointer
+// the game hands over means game commands keep working across reloads.
+use anyhow::{anyhow, bail};
+use engine::{access_global, GameState};
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::{c_char, CStr, CString};
+use std::sync::Mutex;
+
+use crate::commands::{self, EditorCommand};
+use crate::save_states::{self, SlotRequest};
+use crate::{recording, Platform, GAME_STATE};
+
+const OUTPUT_LENGTH: usize = 500;
+
+// The game library's console_command export, takes the command name and the
+// rest of the line and returns false when the command failed
+pub type GameConsoleCommand = unsafe extern "C" fn(*const c_char, *const c_char) -> bool;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ConsoleApi {
+    // Name and help are C strings, registering a name again replaces its help
+    pub register_command: unsafe extern "C" fn(*const c_char, *const c_char),
+    // Adds a C string to the console's output
+    pub print: unsafe extern "C" fn(*const c_char),
+}
+
+impl ConsoleApi {
+    pub fn new() -> Self {
+        Self {
+            register_command: console_register_command,
+            print: console_print,
+        }
+    }
+}
+
+struct HostCommand {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    // What tab completes the first argument to
+    arguments: &'static [&'static str],
+}
+
+const HOST_COMMANDS: &[HostCommand] = &[
+    HostCommand {
+        name: "help",
+        usage: "help [command]",
+        help: "Lists the commands, or tells what one does",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "clear",
+        usage: "clear",
+        help: "Clears the console",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "reload",
+        usage: "reload",
+        help: "Reloads the game library",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "pause",
+        usage: "pause",
+        help: "Pauses the game",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "resume",
+        usage: "resume",
+        help: "Resumes the game",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "step",
+        usage: "step [frames]",
+        help: "Pauses the game and lets it run this many frames, 1 by default",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "record",
+        usage: "record start|stop",
+        help: "Starts recording, or stops and loops the recording or the loop",
+        arguments: &["start", "stop"],
+    },
+    HostCommand {
+        name: "get",
+        usage: "get <path>",
+        help: "Shows part of the game state, like entities[3].position",
+        arguments: &["entities[", "texts", "zmap", "timing_info", "window"],
+    },
+    HostCommand {
+        name: "set",
+        usage: "set <path> <value>",
+        help: "Sets a field of an entity, like entities[3].position.x 40. The value is JSON, or a string.",
+        arguments: &["entities["],
+    },
+    HostCommand {
+        name: "timescale",
+        usage: "timescale [scale]",
+        help: "Shows or sets how fast time passes for the game, 1 is normal",
+        arguments: &[],
+    },
+    HostCommand {
+        name: "save",
+        usage: "save slot <slot>",
+        help: "Saves the state to a save state slot",
+        arguments: &["slot"],
+    },
+    HostCommand {
+        name: "load",
+        usage: "load slot <slot>",
+        help: "Loads the state from a save state slot",
+        arguments: &["slot"],
+    },
+];
+
+struct Console {
+    // Name to help
+    game_commands: BTreeMap<String, String>,
+    output: VecDeque<String>,
+    requests: Vec<String>,
+}
+
+lazy_static! {
+    static ref CONSOLE: Mutex<Console> = Mutex::new(Console {
+        game_commands: BTreeMap::new(),
+        output: VecDeque::new(),
+        requests: vec![],
+    });
+}
+
+pub fn submit(line: &str) {
+    CONSOLE.lock().unwrap().requests.push(line.to_string());
+}
+
+pub fn print(text: impl Into<String>) {
+    let mut console = CONSOLE.lock().unwrap();
+    for line in text.into().lines() {
+        console.output.push_back(line.to_string());
+    }
+    while console.output.len() > OUTPUT_LENGTH {
+        console.output.pop_front();
+    }
+}
+
+pub fn output() -> Vec<String> {
+    CONSOLE.lock().unwrap().output.iter().cloned().collect()
+}
+
+// Call once per frame, right after the editor commands are applied
+pub fn handle_requests(platform: &mut Platform, game_command: Option<GameConsoleCommand>) {
+    let requests = std::mem::take(&mut CONSOLE.lock().unwrap().requests);
+    for line in requests {
+        print(format!("> {}", line));
+        if let Err(error) = run(platform, &line, game_command) {
+            print(format!("{}", error));
+        }
+    }
+}
+
+fn run(platform: &mut Platform, line: &str, game_command: Option<GameConsoleCommand>) -> anyhow::Result<()> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, arguments)) = words.split_first() else {
+        return Ok(());
+    };
+    match (name, arguments) {
+        ("help", []) => {
+            for command in HOST_COMMANDS {
+                print(format!("{:<20} {}", command.usage, command.help));
+            }
+            let game_commands = CONSOLE.lock().unwrap().game_commands.clone();
+            for (name, help) in game_commands {
+                print(format!("{:<20} {}", name, help));
+            }
+        }
+        ("help", [command]) => print(help(command)?),
+        ("clear", []) => CONSOLE.lock().unwrap().output.clear(),
+        ("reload", []) => platform.reload_requested = true,
+        ("pause", []) => commands::apply_command(platform, &EditorCommand::SetPaused(true))?,
+        ("resume", []) => commands::apply_command(platform, &EditorCommand::SetPaused(false))?,
+        ("step", []) | ("step", [_]) => {
+            let frames = match arguments.first() {
+                Some(frames) => frames.parse()?,
+                None => 1,
+            };
+            commands::apply_command(platform, &EditorCommand::SetPaused(true))?;
+            platform.step_frames = frames;
+        }
+        ("record", ["start"]) => recording::record(platform)?,
+        ("record", ["stop"]) => recording::stop_or_play(platform),
+        ("get", [path]) => {
+            let pointer = json_pointer(path)?;
+            let mut result = Err(anyhow!("nothing at {}", path));
+            access_global!(game_state, GAME_STATE, {
+                let game_state: &GameState = &game_state;
+                result = serde_json::to_value(game_state)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|value| value.pointer(&pointer).cloned().ok_or_else(|| anyhow!("nothing at {}", path)));
+            });
+            print(serde_json::to_string_pretty(&result?)?);
+        }
+        ("set", [path, value @ ..]) if !value.is_empty() => {
+            let pointer = json_pointer(path)?;
+            let value = value.join(" ");
+            // Anything that isn't JSON is taken as a string
+            let value = serde_json::from_str(&value).unwrap_or(serde_json::Value::String(value));
+            let Some(rest) = pointer.strip_prefix("/entities/") else {
+                bail!("only entities can be set");
+            };
+            // set entities[3] {...} replaces the whole entity, which is pointer ""
+            let (entity, pointer) = match rest.split_once('/') {
+                Some((entity, field)) => (entity, format!("/{}", field)),
+                None => (rest, String::new()),
+            };
+            commands::apply_command(
+                platform,
+                &EditorCommand::SetField {
+                    entity: entity.parse()?,
+                    pointer,
+                    value,
+                    merge_key: None,
+                },
+            )?;
+        }
+        ("timescale", []) => print(format!("{}", platform.time_scale)),
+        ("timescale", [scale]) => {
+            let scale: f32 = scale.parse()?;
+            if scale.is_nan() || scale < 0.0 {
+                bail!("the time scale can't be negative");
+            }
+            platform.time_scale = scale;
+        }
+        ("save", ["slot", slot]) => save_states::request(SlotRequest::Save(parse_slot(slot)?)),
+        ("load", ["slot", slot]) => save_states::request(SlotRequest::Load(parse_slot(slot)?)),
+        _ => {
+            if let Some(command) = HOST_COMMANDS.iter().find(|command| command.name == name) {
+                bail!("usage: {}", command.usage);
+            }
+            if !CONSOLE.lock().unwrap().game_commands.contains_key(name) {
+                bail!("unknown command {}, try help", name);
+            }
+            let Some(game_command) = game_command else {
+                bail!("the game library has no console_command export");
+            };
+            let name = CString::new(name)?;
+            let arguments = CString::new(arguments.join(" "))?;
+            if !unsafe { game_command(name.as_ptr(), arguments.as_ptr()) } {
+                bail!("{} failed", line);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn help(name: &str) -> anyhow::Result<String> {
+    if let Some(command) = HOST_COMMANDS.iter().find(|command| command.name == name) {
+        return Ok(format!("{}\n  {}", command.usage, command.help));
+    }
+    match CONSOLE.lock().unwrap().game_commands.get(name) {
+        Some(help) => Ok(format!("{}\n  {}", name, help)),
+        None => Err(anyhow!("unknown command {}", name)),
+    }
+}
+
+fn parse_slot(slot: &str) -> anyhow::Result<usize> {
+    let slot = slot.parse()?;
+    if !(1..=save_states::SLOT_COUNT).contains(&slot) {
+        bail!("slots go from 1 to {}", save_states::SLOT_COUNT);
+    }
+    Ok(slot)
+}
+
+// entities[3].position.x becomes /entities/3/position/x
+fn json_pointer(path: &str) -> anyhow::Result<String> {
+    let mut pointer = String::new();
+    for segment in path.split('.') {
+        let (name, mut rest) = segment.split_at(segment.find('[').unwrap_or(segment.len()));
+        if !name.is_empty() {
+            pointer += &format!("/{}", name);
+        }
+        while let Some(index) = rest.strip_prefix('[') {
+            let Some((index, after)) = index.split_once(']') else {
+                bail!("missing ] in {}", path);
+            };
+            pointer += &format!("/{}", index);
+            rest = after;
+        }
+        if !rest.is_empty() || pointer.is_empty() {
+            bail!("can't make sense of {}", path);
+        }
+    }
+    Ok(pointer)
+}
+
+// Completes the word being typed at the end of line, to as much as all the
+// candidates have in common. When there's more than one they're printed.
+pub fn complete(line: &str) -> String {
+    let (head, word) = line.rsplit_once(' ').unwrap_or(("", line));
+    let words: Vec<&str> = head.split_whitespace().collect();
+    let candidates: Vec<String> = match words.as_slice() {
+        [] => {
+            let game_commands = CONSOLE.lock().unwrap().game_commands.keys().cloned().collect::<Vec<_>>();
+            HOST_COMMANDS
+                .iter()
+                .map(|command| command.name.to_string())
+                .chain(game_commands)
+                .collect()
+        }
+        [name] => HOST_COMMANDS
+            .iter()
+            .find(|command| command.name == *name)
+            .map_or(vec![], |command| command.arguments.iter().map(|argument| argument.to_string()).collect()),
+        _ => vec![],
+    };
+    let matches: Vec<&String> = candidates.iter().filter(|candidate| candidate.starts_with(word)).collect();
+    let completed = match matches.as_slice() {
+        [] => return line.to_string(),
+        // No space after an opened index
+        [only] if only.ends_with('[') => only.to_string(),
+        [only] => format!("{} ", only),
+        [first, rest @ ..] => {
+            print(matches.iter().map(|candidate| candidate.as_str()).collect::<Vec<_>>().join("  "));
+            let mut common = first.to_string();
+            for candidate in rest {
+                while !candidate.starts_with(&common) {
+                    common.pop();
+                }
+            }
+            common
+        }
+    };
+    if head.is_empty() && !line.contains(' ') {
+        completed
+    } else {
+        format!("{} {}", head, completed)
+    }
+}
+
+unsafe extern "C" fn console_register_command(name: *const c_char, help: *const c_char) {
+    if name.is_null() {
+        return;
+    }
+    let name = CStr::from_ptr(name).to_string_lossy().into_owned();
+    let help = if help.is_null() {
+        String::new()
+    } else {
+        CStr::from_ptr(help).to_string_lossy().into_owned()
+    };
+    CONSOLE.lock().unwrap().game_commands.insert(name, help);
+}
+
+unsafe extern "C" fn console_print(text: *const c_char) {
+    if !text.is_null() {
+        print(CStr::from_ptr(text).to_string_lossy());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_become_json_pointers() {
+        assert_eq!(json_pointer("entities[3].position.x").unwrap(), "/entities/3/position/x");
+        assert_eq!(json_pointer("entities[3]").unwrap(), "/entities/3");
+        assert_eq!(json_pointer("zmap").unwrap(), "/zmap");
+    }
+
+    #[test]
+    fn broken_paths_are_errors() {
+        assert!(json_pointer("entities[3").is_err());
+        assert!(json_pointer("entities[3]x").is_err());
+        assert!(json_pointer("").is_err());
+    }
+
+    #[test]
+    fn completes_command_names() {
+        assert_eq!(complete("he"), "help ");
+        assert_eq!(complete("rel"), "reload ");
+        // record, reload and resume
+        assert_eq!(complete("re"), "re");
+        assert_eq!(complete("xyz"), "xyz");
+    }
+
+    #[test]
+    fn completes_arguments() {
+        assert_eq!(complete("record s"), "record st");
+        assert_eq!(complete("get ent"), "get entities[");
+        assert_eq!(complete("get z"), "get zmap ");
+        assert_eq!(complete("step "), "step ");
+    }
+}
@@ -0,0 +1,143 @@
+// Cycle counter for the timing info and the profiler.
+//
+// x86 reads the time stamp counter with rdtscp, or rdtsc on CPUs without it,
+// aarch64 reads the virtual counter cntvct_el0 and anything else counts
+// nanoseconds since startup. The x86 and ARM counters tick at a fixed rate
+// on anything recent, so "cycles" is a unit of time more than a measure of
+// work done.
+//
+// Deltas are taken modulo the counter width, so a wrapping counter still
+// gives the right number. What can't be trusted is a delta across cores whose
+// counters aren't in sync. rdtscp tells us which core we read on, and when
+// that changed, or the counter went backwards, the delta is estimated from
+// the wall clock and the rate measured on earlier trusted deltas instead.
+use std::time::Instant;
+
+// Counter bits that are guaranteed to count. ARM only promises 56 before
+// v8.6, masking to that is harmless for 64 bit counters as long as a single
+// delta stays below 2^56 ticks.
+#[cfg(target_arch = "aarch64")]
+const COUNTER_MASK: u64 = (1 << 56) - 1;
+#[cfg(not(target_arch = "aarch64"))]
+const COUNTER_MASK: u64 = u64::MAX;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reading {
+    pub count: u64,
+    // The core the counter was read on, when the CPU tells us
+    pub core: Option<u32>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn read() -> Reading {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::{__cpuid, __rdtscp, _rdtsc};
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::{__cpuid, __rdtscp, _rdtsc};
+    use std::sync::OnceLock;
+
+    static HAS_RDTSCP: OnceLock<bool> = OnceLock::new();
+    let has_rdtscp = *HAS_RDTSCP.get_or_init(|| unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 27) != 0
+    });
+    unsafe {
+        if has_rdtscp {
+            let mut core = 0;
+            let count = __rdtscp(&mut core);
+            Reading {
+                count,
+                core: Some(core),
+            }
+        } else {
+            Reading {
+                count: _rdtsc(),
+                core: None,
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+pub fn read() -> Reading {
+    let count: u64;
+    unsafe {
+        // The isb keeps the read from being hoisted above earlier instructions
+        std::arch::asm!("isb", "mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack));
+    }
+    Reading { count, core: None }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn read() -> Reading {
+    use std::sync::OnceLock;
+
+    static START: OnceLock<Instant> = OnceLock::new();
+    Reading {
+        count: START.get_or_init(Instant::now).elapsed().as_nanos() as u64,
+        core: None,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CycleCounter {
+    last: Reading,
+    last_time: Instant,
+    // Measured on trusted deltas, for estimating untrusted ones
+    ticks_per_second: Option<f64>,
+}
+
+impl Default for CycleCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CycleCounter {
+    pub fn new() -> Self {
+        Self {
+            last: read(),
+            last_time: Instant::now(),
+            ticks_per_second: None,
+        }
+    }
+
+    pub fn last(&self) -> Reading {
+        self.last
+    }
+
+    // Starts counting from now without returning a delta
+    pub fn restart(&mut self) {
+        self.last = read();
+        self.last_time = Instant::now();
+    }
+
+    // Ticks since the last call to elapsed or restart
+    pub fn elapsed(&mut self) -> u64 {
+        let reading = read();
+        let now = Instant::now();
+        let ticks = self.ticks_between(self.last, self.last_time, reading, now);
+        self.last = reading;
+        self.last_time = now;
+        ticks
+    }
+
+    // Ticks from start to a later end, read at start_time and end_time, with
+    // the same checks as elapsed. For timing many overlapping things off one
+    // counter, like the profiler's blocks.
+    pub fn ticks_between(&mut self, start: Reading, start_time: Instant, end: Reading, end_time: Instant) -> u64 {
+        let seconds = end_time.duration_since(start_time).as_secs_f64();
+        let delta = end.count.wrapping_sub(start.count) & COUNTER_MASK;
+        let went_backwards = delta > COUNTER_MASK / 2;
+        let migrated = end.core.is_some() && end.core != start.core;
+        if went_backwards || migrated {
+            // At worst a frame's worth of ticks is lost before we know the rate
+            return self
+                .ticks_per_second
+                .map_or(0, |ticks_per_second| (ticks_per_second * seconds) as u64);
+        }
+        if seconds > 0.0 {
+            self.ticks_per_second = Some(delta as f64 / seconds);
+        }
+        delta
+    }
+}
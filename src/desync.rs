@@ -0,0 +1,83 @@
+// Desync detection for recordings.
+//
+// While recording, every --checkpoint-interval frames we store a hash of the
+// serialized GameState (and the state itself). Playback compares against
+// those and reports the first frame where they differ, dumping both states as
+// JSON next to each other so they can be diffed.
+use engine::GameState;
+use std::path::{Path, PathBuf};
+
+use crate::{replay, StateCheckpoint};
+
+// The host flags recordings in GameState.recording, which isn't the game's
+// doing
+fn normalized(game_state: &GameState) -> GameState {
+    let mut game_state = game_state.clone();
+    game_state.recording = false;
+    game_state
+}
+
+pub fn hash_game_state(game_state: &GameState) -> u64 {
+    let bytes = bincode::serialize(&normalized(game_state)).unwrap();
+    replay::fnv1a(&bytes)
+}
+
+pub fn checkpoint(frame: usize, game_state: &GameState) -> StateCheckpoint {
+    let game_state = normalized(game_state);
+    StateCheckpoint {
+        frame,
+        hash: hash_game_state(&game_state),
+        game_state,
+    }
+}
+
+// Logs what differs between the recorded and the replayed state and dumps
+// both to <dump_prefix>.expected.json and <dump_prefix>.actual.json.
+pub fn report(checkpoint: &StateCheckpoint, actual: &GameState, dump_prefix: &Path) {
+    let actual = normalized(actual);
+    log_error!(
+        "desync",
+        "Desync at frame {}: expected state hash {:016x}, got {:016x}",
+        checkpoint.frame,
+        checkpoint.hash,
+        hash_game_state(&actual)
+    );
+    let expected = &checkpoint.game_state;
+    if expected.entities.len() != actual.entities.len() {
+        log_error!(
+            "desync",
+            "  entity count: expected {}, got {}",
+            expected.entities.len(),
+            actual.entities.len()
+        );
+    }
+    for (index, (expected_entity, actual_entity)) in
+        expected.entities.iter().zip(actual.entities.iter()).enumerate()
+    {
+        if serde_json::to_value(expected_entity).ok() != serde_json::to_value(actual_entity).ok() {
+            log_error!("desync", "  entities[{}] differs", index);
+        }
+    }
+    if serde_json::to_value(&expected.zmap).ok() != serde_json::to_value(&actual.zmap).ok() {
+        log_error!("desync", "  zmap differs");
+    }
+    if expected.texts != actual.texts {
+        log_error!("desync", "  texts differ");
+    }
+    for (suffix, game_state) in [("expected", expected), ("actual", &actual)] {
+        let path = dump_path(dump_prefix, suffix);
+        let result = std::fs::File::create(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|file| Ok(serde_json::to_writer_pretty(file, game_state)?));
+        match result {
+            Ok(()) => log_info!("desync", "  wrote {}", path.display()),
+            Err(error) => log_error!("desync", "  failed to write {}: {:?}", path.display(), error),
+        }
+    }
+}
+
+fn dump_path(dump_prefix: &Path, suffix: &str) -> PathBuf {
+    let mut path = dump_prefix.as_os_str().to_owned();
+    path.push(format!(".{}.json", suffix));
+    PathBuf::from(path)
+}
@@ -8,6 +8,23 @@ use fermium::{
     error::SDL_GetErrorMsg, events::*, video::{SDL_GL_CreateContext, SDL_GL_GetProcAddress, SDL_GL_MakeCurrent, SDL_GL_SetSwapInterval, SDL_GL_SwapWindow, SDL_GLprofile, SDL_Window, SDL_GL_CONTEXT_PROFILE_CORE, SDL_WINDOW_OPENGL}
 };
 use std::ffi::CStr;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use engine::access_global;
+use crate::commands::{self, EditorCommand, EditorSnapshot};
+use crate::console;
+use crate::frame_stats;
+use crate::log::{self, Level, LogRecord};
+use crate::profiler::FrameProfile;
+use crate::rewind::{self, RewindRequest};
+use crate::save_states::{self, SaveSlot, SlotRequest};
+use crate::trace;
+use crate::tweaks::{self, TweakValue};
+use crate::zmap;
+use crate::{FRAME_STATS, MEMORY_STATS, PROFILE_FRAMES, REWIND_STATUS, SAVE_SLOTS};
+
+// GL textures for the save state thumbnails, with the saved_at of the state
+// they were made from so we notice when a slot gets overwritten.
+type ThumbnailTextures = Vec<Option<(u64, glow::Texture)>>;
 
 pub fn get_error() -> String {
     unsafe {
@@ -53,6 +70,7 @@ macro_rules! gl_get_attribute {
 }
 
 pub fn spawn_window() {
+    trace::name_thread("editor");
     let window: *mut SDL_Window;
     unsafe {
 
@@ -92,10 +110,18 @@ pub fn spawn_window() {
     /* create platform and renderer */
     let mut platform = imgui_backend::ImguiBackend::init(&mut imgui);
     let mut renderer = imgui_renderer::AutoRenderer::initialize(gl, &mut imgui).unwrap();
+    let gl = renderer.gl_context().clone();
+    let mut thumbnails: ThumbnailTextures = vec![None; save_states::SLOT_COUNT];
+    let mut profiler_view = ProfilerView::default();
+    let mut inspector_view = InspectorView::default();
+    let mut log_view = LogView::default();
+    let mut console_view = ConsoleView::default();
+    let mut scene_view = SceneView::default();
 
     /* start main loop */
     let mut event: SDL_Event = Default::default();
     'main: loop {
+        let frame_start = Instant::now();
         unsafe {
             while SDL_PollEvent(&mut event) == 1 {
                 if event.type_.0 == SDL_QUIT.0 {
@@ -111,7 +137,28 @@ pub fn spawn_window() {
 
         let ui = imgui.new_frame();
         /* create imgui UI here */
-        ui.show_demo_window(&mut true);
+        let snapshot = commands::snapshot();
+        // Text fields have their own undo
+        if ui.io().key_ctrl && !ui.io().want_text_input {
+            if ui.is_key_pressed(imgui::Key::Z) {
+                commands::send(EditorCommand::Undo);
+            } else if ui.is_key_pressed(imgui::Key::Y) {
+                commands::send(EditorCommand::Redo);
+            }
+        }
+        game_panel(ui, &snapshot);
+        scene_panel(ui, &snapshot, &mut scene_view);
+        inspector_panel(ui, &snapshot, &mut inspector_view);
+        history_panel(ui, &snapshot);
+        zmap_panel(ui, &snapshot, &mut inspector_view);
+        save_states_panel(ui, &gl, &mut thumbnails);
+        rewind_panel(ui);
+        memory_panel(ui);
+        profiler_panel(ui, &mut profiler_view);
+        frame_time_panel(ui);
+        log_panel(ui, &mut log_view);
+        console_panel(ui, &mut console_view);
+        tweaks_panel(ui, &snapshot, &mut inspector_view);
 
         /* render */
         let draw_data = imgui.render();
@@ -121,5 +168,915 @@ pub fn spawn_window() {
         unsafe {
             SDL_GL_SwapWindow(window);
         }
+        trace::complete_event("editor frame", frame_start, Instant::now());
+    }
+}
+
+fn memory_panel(ui: &imgui::Ui) {
+    ui.window("Memory")
+        .size([360.0, 300.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            access_global!(memory_stats, MEMORY_STATS, {
+                for arena in memory_stats.iter() {
+                    ui.separator();
+                    ui.text(format!("{} arena", arena.name));
+                    let fraction = if arena.size > 0 {
+                        arena.used as f32 / arena.size as f32
+                    } else {
+                        0.0
+                    };
+                    imgui::ProgressBar::new(fraction)
+                        .overlay_text(format!(
+                            "{} / {}",
+                            describe_bytes(arena.used),
+                            describe_bytes(arena.size)
+                        ))
+                        .build(ui);
+                    ui.text(format!("High water mark: {}", describe_bytes(arena.high_water_mark)));
+                    for (tag, bytes) in arena.tags.iter() {
+                        ui.bullet_text(format!("{}: {}", tag, describe_bytes(*bytes)));
+                    }
+                }
+            });
+        });
+}
+
+struct InspectorView {
+    selected: Option<usize>,
+    // Bumped whenever a drag starts, so each drag is one undo step
+    merge_key: u64,
+    // Index into the snapshot's templates
+    template: usize,
+    // Index into the z-layers, 0 is no layer
+    layer: usize,
+    duplicate_offset: [f32; 3],
+    template_name: String,
+}
+
+impl Default for InspectorView {
+    fn default() -> Self {
+        Self {
+            selected: None,
+            merge_key: 0,
+            template: 0,
+            layer: 0,
+            duplicate_offset: [1.0, 1.0, 0.0],
+            template_name: String::new(),
+        }
+    }
+}
+
+fn game_panel(ui: &imgui::Ui, snapshot: &EditorSnapshot) {
+    ui.window("Game")
+        .size([360.0, 100.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            ui.text(format!("Frame {}", snapshot.frame));
+            if snapshot.paused {
+                if ui.button("Resume") {
+                    commands::send(EditorCommand::SetPaused(false));
+                }
+            } else if ui.button("Pause") {
+                commands::send(EditorCommand::SetPaused(true));
+            }
+            ui.same_line();
+            if ui.button("Reload") {
+                commands::send(EditorCommand::Reload);
+            }
+            ui.same_line();
+            if snapshot.recording {
+                if ui.button("Stop and loop") {
+                    commands::send(EditorCommand::StopRecording);
+                }
+            } else if snapshot.playing_back {
+                if ui.button("Stop loop") {
+                    commands::send(EditorCommand::StopRecording);
+                }
+            } else if ui.button("Record") {
+                commands::send(EditorCommand::StartRecording);
+            }
+        });
+}
+
+#[derive(Default)]
+struct SceneView {
+    path: String,
+}
+
+fn scene_panel(ui: &imgui::Ui, snapshot: &EditorSnapshot, view: &mut SceneView) {
+    if view.path.is_empty() {
+        view.path = snapshot
+            .scene_path
+            .as_ref()
+            .map_or_else(|| "scene.json".to_string(), |path| path.display().to_string());
     }
-}
\ No newline at end of file
+    // The ### keeps it the same window with or without the star
+    let title = if snapshot.scene_unsaved { "Scene *###Scene" } else { "Scene###Scene" };
+    ui.window(title)
+        .size([360.0, 110.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let name = snapshot
+                .scene_path
+                .as_ref()
+                .map_or_else(|| "No scene".to_string(), |path| path.display().to_string());
+            if snapshot.scene_unsaved {
+                ui.text_colored([1.0, 0.8, 0.2, 1.0], format!("{}, unsaved changes", name));
+            } else {
+                ui.text(name);
+            }
+            ui.input_text("Path", &mut view.path).build();
+            if ui.button("Save") {
+                commands::send(EditorCommand::SaveScene(view.path.clone().into()));
+            }
+            ui.same_line();
+            let load_label = if snapshot.scene_unsaved { "Load, discarding changes" } else { "Load" };
+            if ui.button(load_label) {
+                commands::send(EditorCommand::LoadScene(view.path.clone().into()));
+            }
+        });
+}
+
+fn history_panel(ui: &imgui::Ui, snapshot: &EditorSnapshot) {
+    let history = &snapshot.history;
+    ui.window("History")
+        .size([300.0, 300.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let locked = snapshot.recording || snapshot.playing_back;
+            ui.disabled(locked || history.cursor == 0, || {
+                if ui.button("Undo (Ctrl+Z)") {
+                    commands::send(EditorCommand::Undo);
+                }
+            });
+            ui.same_line();
+            ui.disabled(locked || history.cursor == history.descriptions.len(), || {
+                if ui.button("Redo (Ctrl+Y)") {
+                    commands::send(EditorCommand::Redo);
+                }
+            });
+            ui.separator();
+            for (index, description) in history.descriptions.iter().enumerate() {
+                if index < history.cursor {
+                    ui.bullet_text(description);
+                } else {
+                    // Undone, can be redone
+                    ui.text_disabled(format!("  {}", description));
+                }
+            }
+        });
+}
+
+fn inspector_panel(ui: &imgui::Ui, snapshot: &EditorSnapshot, view: &mut InspectorView) {
+    let entities = &snapshot.game_state.entities;
+    ui.window("Entities")
+        .size([360.0, 500.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            ui.text(format!("{} entities", entities.len()));
+            let locked = snapshot.recording || snapshot.playing_back;
+            if locked {
+                ui.text_disabled("Stop the recording or the loop to edit");
+            }
+            ui.disabled(locked, || create_entity(ui, snapshot, view));
+            ui.child_window("entity list").size([0.0, 150.0]).build(|| {
+                for index in 0..entities.len() {
+                    let selected = view.selected == Some(index);
+                    if ui.selectable_config(format!("Entity {}", index)).selected(selected).build() {
+                        view.selected = Some(index);
+                    }
+                }
+            });
+            let Some(index) = view.selected.filter(|index| *index < entities.len()) else {
+                return;
+            };
+            let entity = &entities[index];
+            ui.separator();
+            ui.text(format!("Entity {}", index));
+            // Not the fields tree below, that only shows the entity
+            let disabled = ui.begin_disabled(locked);
+            ui.same_line();
+            if ui.button("Delete") {
+                commands::send(EditorCommand::DeleteEntity(index));
+                view.selected = None;
+            }
+            imgui::Drag::new("Offset").speed(0.1).build_array(ui, &mut view.duplicate_offset);
+            ui.same_line();
+            if ui.button("Duplicate") {
+                commands::send(EditorCommand::DuplicateEntity {
+                    entity: index,
+                    offset: view.duplicate_offset,
+                });
+                // Copies go on the end
+                view.selected = Some(entities.len());
+            }
+            ui.input_text("##template name", &mut view.template_name)
+                .hint("template name")
+                .build();
+            ui.same_line();
+            ui.disabled(view.template_name.trim().is_empty(), || {
+                if ui.button("Save as template") {
+                    commands::send(EditorCommand::SaveTemplate {
+                        entity: index,
+                        name: view.template_name.trim().to_string(),
+                    });
+                }
+            });
+            let mut position = [entity.position.x, entity.position.y, entity.position.z];
+            let changed = imgui::Drag::new("Position").speed(0.1).build_array(ui, &mut position);
+            if ui.is_item_activated() {
+                view.merge_key += 1;
+            }
+            if changed {
+                commands::send(EditorCommand::SetPosition {
+                    entity: index,
+                    position,
+                    merge_key: Some(view.merge_key),
+                });
+            }
+            disabled.end();
+            if let Ok(value) = serde_json::to_value(entity) {
+                json_tree(ui, "Fields", &value);
+            }
+        });
+}
+
+// Where a dragged zmap member comes from, indices into the layers the panel
+// showed when the drag started
+#[derive(Debug, Clone, Copy)]
+struct ZmapPayload {
+    layer: usize,
+    member: usize,
+}
+
+const ZMAP_PAYLOAD: &str = "ZMAP_MEMBER";
+
+fn zmap_panel(ui: &imgui::Ui, snapshot: &EditorSnapshot, inspector: &mut InspectorView) {
+    let visibility = &snapshot.layer_visibility;
+    ui.window("Z-layers")
+        .size([300.0, 400.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let layers = match zmap::layers(&snapshot.game_state) {
+                Ok(layers) => layers,
+                Err(error) => {
+                    ui.text(format!("Can't edit the zmap: {}", error));
+                    if let Ok(value) = serde_json::to_value(&snapshot.game_state.zmap) {
+                        json_tree(ui, "zmap", &value);
+                    }
+                    return;
+                }
+            };
+            ui.text("The game draws the layers in this order");
+            ui.text("Drag members to move them between layers");
+            if visibility.solo.is_some() && ui.button("Unsolo") {
+                commands::send(EditorCommand::SetLayerSolo(None));
+            }
+            for (layer_index, layer) in layers.iter().enumerate() {
+                let _id = ui.push_id_usize(layer_index);
+                ui.separator();
+                let mut hidden = visibility.hidden.contains(&layer.key);
+                if ui.checkbox("Hide", &mut hidden) {
+                    commands::send(EditorCommand::SetLayerHidden {
+                        layer: layer.key.clone(),
+                        hidden,
+                    });
+                }
+                ui.same_line();
+                let mut solo = visibility.solo.as_ref() == Some(&layer.key);
+                if ui.checkbox("Solo", &mut solo) {
+                    commands::send(EditorCommand::SetLayerSolo(solo.then(|| layer.key.clone())));
+                }
+                ui.same_line();
+                let label = format!("Layer {} ({})", layer.key, layer.members.len());
+                if visibility.is_visible(&layer.key) {
+                    ui.text(label);
+                } else {
+                    ui.text_disabled(label);
+                }
+                // Dropping on the layer itself puts the member at the end
+                zmap_drop_target(ui, &layers, layer_index, None);
+                for (member_index, member) in layer.members.iter().enumerate() {
+                    // Members that are numbers are taken to be entity indices
+                    let entity = member.as_u64().map(|entity| entity as usize);
+                    let label = match entity {
+                        Some(entity) => format!("Entity {}##{}", entity, member_index),
+                        None => format!("{}##{}", member, member_index),
+                    };
+                    let selected = entity.is_some() && entity == inspector.selected;
+                    if ui.selectable_config(label).selected(selected).build() && entity.is_some() {
+                        inspector.selected = entity;
+                    }
+                    let payload = ZmapPayload {
+                        layer: layer_index,
+                        member: member_index,
+                    };
+                    if let Some(tooltip) = ui.drag_drop_source_config(ZMAP_PAYLOAD).begin_payload(payload) {
+                        ui.text(format!("Move {}", member));
+                        tooltip.end();
+                    }
+                    // Dropping on a member puts the dragged one before it
+                    zmap_drop_target(ui, &layers, layer_index, Some(member_index));
+                }
+            }
+        });
+}
+
+fn zmap_drop_target(ui: &imgui::Ui, layers: &[zmap::Layer], layer: usize, index: Option<usize>) {
+    let Some(target) = ui.drag_drop_target() else {
+        return;
+    };
+    if let Some(Ok(payload)) = target.accept_payload::<ZmapPayload, _>(ZMAP_PAYLOAD, imgui::DragDropFlags::empty()) {
+        let dragged = layers
+            .get(payload.data.layer)
+            .and_then(|dragged_layer| dragged_layer.members.get(payload.data.member));
+        if let Some(member) = dragged {
+            commands::send(EditorCommand::MoveToLayer {
+                member: member.clone(),
+                layer: layers[layer].key.clone(),
+                index,
+            });
+        }
+    }
+    target.pop();
+}
+
+fn create_entity(ui: &imgui::Ui, snapshot: &EditorSnapshot, view: &mut InspectorView) {
+    let templates = &snapshot.templates;
+    if templates.is_empty() {
+        ui.text_disabled("Save an entity as a template to create more like it");
+        return;
+    }
+    view.template = view.template.min(templates.len() - 1);
+    let mut layers = vec!["No layer".to_string()];
+    if let Ok(zmap_layers) = zmap::layers(&snapshot.game_state) {
+        layers.extend(zmap_layers.into_iter().map(|layer| format!("Layer {}", layer.key)));
+    }
+    view.layer = view.layer.min(layers.len() - 1);
+    ui.set_next_item_width(120.0);
+    ui.combo_simple_string("##template", &mut view.template, templates);
+    ui.same_line();
+    ui.set_next_item_width(100.0);
+    ui.combo_simple_string("##layer", &mut view.layer, &layers);
+    ui.same_line();
+    if ui.button("Create") {
+        let layer = match view.layer {
+            0 => None,
+            layer => zmap::layers(&snapshot.game_state)
+                .ok()
+                .and_then(|zmap_layers| zmap_layers.into_iter().nth(layer - 1))
+                .map(|layer| layer.key),
+        };
+        commands::send(EditorCommand::CreateEntity {
+            template: templates[view.template].clone(),
+            layer,
+        });
+        // New entities go on the end
+        view.selected = Some(snapshot.game_state.entities.len());
+    }
+}
+
+// Read only view of anything serializable
+fn json_tree(ui: &imgui::Ui, label: &str, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            ui.tree_node_config(label).default_open(true).build(|| {
+                for (name, field) in fields.iter() {
+                    json_tree(ui, name, field);
+                }
+            });
+        }
+        serde_json::Value::Array(items) => {
+            ui.tree_node_config(format!("{} [{}]", label, items.len())).build(|| {
+                for (index, item) in items.iter().enumerate() {
+                    json_tree(ui, &index.to_string(), item);
+                }
+            });
+        }
+        _ => ui.text(format!("{}: {}", label, value)),
+    }
+}
+
+fn frame_time_panel(ui: &imgui::Ui) {
+    let mut stats = frame_stats::FrameStats::default();
+    access_global!(frame_stats, FRAME_STATS, {
+        stats = frame_stats.clone();
+    });
+    ui.window("Frame time")
+        .size([420.0, 360.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let summary = stats.summary();
+            let frame_times: Vec<f32> = stats.frame_times.iter().copied().collect();
+            ui.plot_lines("##frame times", &frame_times)
+                .scale_min(0.0)
+                .scale_max((2.0 * stats.target_milliseconds).max(summary.max))
+                .graph_size([0.0, 80.0])
+                .overlay_text(format!("target {:.2}ms", stats.target_milliseconds))
+                .build();
+            ui.text(format!(
+                "min {:.2}ms  avg {:.2}ms  p99 {:.2}ms  max {:.2}ms",
+                summary.min, summary.average, summary.p99, summary.max
+            ));
+            ui.text(format!(
+                "Missed {} of {} frames",
+                stats.missed_frame_count, stats.frame_count
+            ));
+            ui.separator();
+            ui.text("Hitches, newest first");
+            ui.child_window("hitches").build(|| {
+                for hitch in stats.hitches.iter().rev() {
+                    let mut line = format!(
+                        "Frame {}: {:.2}ms ({:.2}ms of work)",
+                        hitch.frame, hitch.milliseconds, hitch.work_milliseconds
+                    );
+                    if let Some((name, milliseconds)) = hitch.slowest_block.as_ref() {
+                        line += &format!(", mostly {} ({:.2}ms)", name, milliseconds);
+                    }
+                    if !hitch.notes.is_empty() {
+                        line += &format!(", during {}", hitch.notes.join(", "));
+                    }
+                    ui.bullet_text(line);
+                }
+            });
+        });
+}
+
+fn tweaks_panel(ui: &imgui::Ui, snapshot: &EditorSnapshot, view: &mut InspectorView) {
+    let all_tweaks = tweaks::all();
+    ui.window("Tweaks")
+        .size([360.0, 400.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            if all_tweaks.is_empty() {
+                ui.text("The game hasn't declared any tweakables");
+                return;
+            }
+            // A recording puts its own values back when it plays
+            let locked = snapshot.recording || snapshot.playing_back;
+            if locked {
+                ui.text_disabled("Stop the recording or the loop to tweak");
+            }
+            let _disabled = ui.begin_disabled(locked);
+            // Group name to the tweaks in it, with their names within the group
+            let mut groups: Vec<(&str, Vec<(&str, &String, &tweaks::Tweak)>)> = vec![];
+            for (name, tweak) in all_tweaks.iter() {
+                let (group, short_name) = name.rsplit_once('/').unwrap_or(("", name));
+                match groups.iter_mut().find(|(other, _)| *other == group) {
+                    Some((_, group_tweaks)) => group_tweaks.push((short_name, name, tweak)),
+                    None => groups.push((group, vec![(short_name, name, tweak)])),
+                }
+            }
+            for (group, group_tweaks) in groups {
+                let label = if group.is_empty() { "Ungrouped" } else { group };
+                if !ui.collapsing_header(label, imgui::TreeNodeFlags::DEFAULT_OPEN) {
+                    continue;
+                }
+                for (short_name, name, tweak) in group_tweaks {
+                    let _id = ui.push_id(name.as_str());
+                    let edited = match tweak.value {
+                        TweakValue::F32(mut value) => {
+                            ui.slider(short_name, tweak.min as f32, tweak.max as f32, &mut value)
+                                .then_some(TweakValue::F32(value))
+                        }
+                        TweakValue::I32(mut value) => {
+                            ui.slider(short_name, tweak.min as i32, tweak.max as i32, &mut value)
+                                .then_some(TweakValue::I32(value))
+                        }
+                        TweakValue::Bool(mut value) => {
+                            ui.checkbox(short_name, &mut value).then_some(TweakValue::Bool(value))
+                        }
+                        TweakValue::Color(mut value) => {
+                            ui.color_edit4(short_name, &mut value).then_some(TweakValue::Color(value))
+                        }
+                    };
+                    if ui.is_item_activated() {
+                        view.merge_key += 1;
+                    }
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text(format!("{}, default {:?}", name, tweak.default));
+                    }
+                    if let Some(value) = edited {
+                        commands::send(EditorCommand::SetTweak {
+                            name: name.clone(),
+                            value,
+                            merge_key: Some(view.merge_key),
+                        });
+                    }
+                    // Saved once a drag ends rather than on every step of it
+                    let finished = ui.is_item_deactivated_after_edit()
+                        || matches!((edited, tweak.value), (Some(_), TweakValue::Bool(_)));
+                    if finished {
+                        commands::send(EditorCommand::SaveTweaks);
+                    }
+                }
+            }
+        });
+}
+
+#[derive(Default)]
+struct ConsoleView {
+    input: String,
+    // Submitted lines, oldest first
+    history: Vec<String>,
+    // Where up and down have got to in the history, None is the line being typed
+    history_index: Option<usize>,
+}
+
+struct ConsoleInput<'a> {
+    history: &'a [String],
+    history_index: &'a mut Option<usize>,
+}
+
+impl imgui::InputTextCallbackHandler for ConsoleInput<'_> {
+    fn on_completion(&mut self, mut data: imgui::TextCallbackData) {
+        let completed = console::complete(data.str());
+        data.clear();
+        data.push_str(&completed);
+    }
+
+    fn on_history(&mut self, direction: imgui::HistoryDirection, mut data: imgui::TextCallbackData) {
+        if self.history.is_empty() {
+            return;
+        }
+        *self.history_index = match (direction, *self.history_index) {
+            (imgui::HistoryDirection::Up, None) => Some(self.history.len() - 1),
+            (imgui::HistoryDirection::Up, Some(index)) => Some(index.saturating_sub(1)),
+            (imgui::HistoryDirection::Down, Some(index)) if index + 1 < self.history.len() => Some(index + 1),
+            (imgui::HistoryDirection::Down, _) => None,
+        };
+        data.clear();
+        if let Some(index) = *self.history_index {
+            data.push_str(&self.history[index]);
+        }
+    }
+}
+
+fn console_panel(ui: &imgui::Ui, view: &mut ConsoleView) {
+    let output = console::output();
+    ui.window("Console")
+        .size([600.0, 300.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let footer_height = ui.frame_height_with_spacing();
+            ui.child_window("console output").size([0.0, -footer_height]).build(|| {
+                for line in output.iter() {
+                    ui.text(line);
+                }
+                // Only follow along while scrolled to the bottom
+                if ui.scroll_y() >= ui.scroll_max_y() {
+                    ui.set_scroll_here_y_with_ratio(1.0);
+                }
+            });
+            let input = ConsoleInput {
+                history: &view.history,
+                history_index: &mut view.history_index,
+            };
+            let submitted = ui
+                .input_text("##command", &mut view.input)
+                .hint("help, Tab completes, up and down go through the history")
+                .enter_returns_true(true)
+                .callback(imgui::InputTextCallback::COMPLETION | imgui::InputTextCallback::HISTORY, input)
+                .build();
+            if submitted {
+                let line = view.input.trim().to_string();
+                if !line.is_empty() {
+                    console::submit(&line);
+                    if view.history.last() != Some(&line) {
+                        view.history.push(line);
+                    }
+                }
+                view.input.clear();
+                view.history_index = None;
+                // Enter takes the focus away, keep typing
+                ui.set_keyboard_focus_here_with_offset(imgui::FocusedWidget::Previous);
+            }
+        });
+}
+
+struct LogView {
+    // What the console shows, its own copy so pausing it freezes the view
+    records: Vec<LogRecord>,
+    last_sequence: Option<u64>,
+    paused: bool,
+    auto_scroll: bool,
+    filter: String,
+    // Index into Level::ALL of the least severe level shown
+    min_level: usize,
+    // Index into the tags seen so far, 0 is all of them
+    tag: usize,
+    export_path: String,
+    export_result: Option<String>,
+}
+
+impl Default for LogView {
+    fn default() -> Self {
+        Self {
+            records: vec![],
+            last_sequence: None,
+            paused: false,
+            auto_scroll: true,
+            filter: String::new(),
+            min_level: 0,
+            tag: 0,
+            export_path: "log.txt".to_string(),
+            export_result: None,
+        }
+    }
+}
+
+fn log_panel(ui: &imgui::Ui, view: &mut LogView) {
+    if !view.paused {
+        let new_records = log::records_since(view.last_sequence);
+        if let Some(last) = new_records.last() {
+            view.last_sequence = Some(last.sequence);
+        }
+        view.records.extend(new_records);
+        let excess = view.records.len().saturating_sub(log::LOG_LENGTH);
+        view.records.drain(..excess);
+    }
+    ui.window("Log")
+        .size([600.0, 300.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let level_names = Level::ALL.map(|level| level.to_string());
+            ui.set_next_item_width(80.0);
+            ui.combo_simple_string("Level", &mut view.min_level, &level_names);
+            ui.same_line();
+            let mut tags = vec!["all".to_string()];
+            for record in view.records.iter() {
+                if !tags.contains(&record.tag) {
+                    tags.push(record.tag.clone());
+                }
+            }
+            tags[1..].sort();
+            view.tag = view.tag.min(tags.len() - 1);
+            ui.set_next_item_width(120.0);
+            ui.combo_simple_string("Tag", &mut view.tag, &tags);
+            ui.same_line();
+            ui.set_next_item_width(160.0);
+            ui.input_text("Filter", &mut view.filter).build();
+            ui.checkbox("Pause", &mut view.paused);
+            ui.same_line();
+            ui.checkbox("Auto-scroll", &mut view.auto_scroll);
+            ui.same_line();
+            if ui.button("Clear") {
+                view.records.clear();
+            }
+            ui.same_line();
+            ui.set_next_item_width(160.0);
+            ui.input_text("##export path", &mut view.export_path).build();
+            ui.same_line();
+            let min_level = Level::ALL[view.min_level];
+            let tag = (view.tag > 0).then(|| tags[view.tag].as_str());
+            let filter = view.filter.to_lowercase();
+            let shown = |record: &&LogRecord| {
+                record.level >= min_level
+                    && tag.map_or(true, |tag| record.tag == tag)
+                    && (filter.is_empty() || record.message.to_lowercase().contains(&filter))
+            };
+            if ui.button("Export") {
+                let path = std::path::Path::new(&view.export_path);
+                view.export_result = Some(match log::export(path, view.records.iter().filter(shown)) {
+                    Ok(()) => format!("Exported to {}", path.display()),
+                    Err(error) => format!("Failed to export: {}", error),
+                });
+            }
+            if let Some(export_result) = view.export_result.as_ref() {
+                ui.same_line();
+                ui.text(export_result);
+            }
+            ui.separator();
+            ui.child_window("log lines").build(|| {
+                for record in view.records.iter().filter(shown) {
+                    let line = record.to_string();
+                    match record.level {
+                        Level::Debug => ui.text_disabled(line),
+                        Level::Info => ui.text(line),
+                        Level::Warn => ui.text_colored([1.0, 0.8, 0.2, 1.0], line),
+                        Level::Error => ui.text_colored([1.0, 0.3, 0.3, 1.0], line),
+                    }
+                }
+                // Only follow along while scrolled to the bottom
+                if view.auto_scroll && ui.scroll_y() >= ui.scroll_max_y() {
+                    ui.set_scroll_here_y_with_ratio(1.0);
+                }
+            });
+        });
+}
+
+#[derive(Default)]
+struct ProfilerView {
+    // Frame number to show, None follows the newest frame
+    frame: Option<usize>,
+}
+
+fn profiler_panel(ui: &imgui::Ui, view: &mut ProfilerView) {
+    let mut frames = vec![];
+    access_global!(profile_frames, PROFILE_FRAMES, {
+        frames = profile_frames.iter().cloned().collect::<Vec<FrameProfile>>();
+    });
+    ui.window("Profiler")
+        .size([420.0, 360.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            let trace_label = if trace::is_tracing() { "Stop trace (F11)" } else { "Start trace (F11)" };
+            if ui.button(trace_label) {
+                trace::toggle();
+            }
+            let Some(newest) = frames.last() else {
+                ui.text("No frames yet");
+                return;
+            };
+            let oldest = frames[0].frame;
+            let mut frame_number = view.frame.unwrap_or(newest.frame).clamp(oldest, newest.frame);
+            if ui.slider("Frame", oldest, newest.frame, &mut frame_number) {
+                view.frame = Some(frame_number);
+            }
+            let mut follow = view.frame.is_none();
+            if ui.checkbox("Follow newest", &mut follow) {
+                view.frame = if follow { None } else { Some(frame_number) };
+            }
+            let Some(frame) = frames.iter().find(|frame| frame.frame == frame_number) else {
+                return;
+            };
+            ui.text(format!(
+                "Frame {}: {:.2}ms, {:.2} megacycles",
+                frame.frame,
+                frame.milliseconds,
+                frame.cycles as f32 / 1_000_000.0
+            ));
+            ui.separator();
+            profile_tree(ui, frame, None);
+        });
+}
+
+fn profile_tree(ui: &imgui::Ui, frame: &FrameProfile, parent: Option<usize>) {
+    for (index, block) in frame.blocks.iter().enumerate() {
+        if block.parent != parent {
+            continue;
+        }
+        let has_children = frame.blocks.iter().any(|child| child.parent == Some(index));
+        let label = format!(
+            "{}: {} hits, {:.3} megacycles, {:.3}ms##{}",
+            block.name,
+            block.hit_count,
+            block.cycles as f32 / 1_000_000.0,
+            block.milliseconds,
+            index
+        );
+        ui.tree_node_config(label)
+            .default_open(true)
+            .leaf(!has_children)
+            .build(|| profile_tree(ui, frame, Some(index)));
+    }
+}
+
+fn describe_bytes(bytes: usize) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MB", bytes as f32 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KB", bytes as f32 / 1024.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+fn rewind_panel(ui: &imgui::Ui) {
+    let mut status = rewind::RewindStatus::default();
+    access_global!(rewind_status, REWIND_STATUS, {
+        status = rewind_status.clone();
+    });
+    ui.window("Rewind")
+        .size([360.0, 140.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            ui.text("F6 steps back, F7 steps forward, F8 resumes");
+            ui.text(format!(
+                "{} frames, {} of {}",
+                status.frames,
+                describe_bytes(status.bytes),
+                describe_bytes(status.budget)
+            ));
+            if status.frames == 0 {
+                return;
+            }
+            let newest = status.frames - 1;
+            let mut frame = status.cursor.unwrap_or(newest);
+            if ui.slider("Frame", 0, newest, &mut frame) {
+                rewind::request(RewindRequest::Seek(frame));
+            }
+            if ui.button("Step back") {
+                rewind::request(RewindRequest::StepBack);
+            }
+            ui.same_line();
+            if ui.button("Step forward") {
+                rewind::request(RewindRequest::StepForward);
+            }
+            ui.same_line();
+            if status.cursor.is_some() {
+                if ui.button("Resume") {
+                    rewind::request(RewindRequest::Resume);
+                }
+                ui.same_line();
+                ui.text(format!("{} frames back", newest - frame));
+                if let Ok(inputs) = serde_json::to_value(&status.inputs) {
+                    json_tree(ui, "Input", &inputs);
+                }
+            } else {
+                ui.text("Live");
+            }
+        });
+}
+
+fn save_states_panel(ui: &imgui::Ui, gl: &glow::Context, thumbnails: &mut ThumbnailTextures) {
+    ui.window("Save states")
+        .size([360.0, 600.0], imgui::Condition::FirstUseEver)
+        .build(|| {
+            ui.text("Ctrl+Shift+1..9 saves, Ctrl+1..9 loads");
+            access_global!(save_slots, SAVE_SLOTS, {
+                for slot in 1..=save_states::SLOT_COUNT {
+                    let _id = ui.push_id_usize(slot);
+                    ui.separator();
+                    let save_slot = save_slots[slot - 1].as_ref();
+                    match save_slot {
+                        Some(save_slot) => {
+                            ui.text(format!("Slot {}, saved {}", slot, describe_age(save_slot.saved_at)));
+                            if let Some(texture) = thumbnail_texture(gl, &mut thumbnails[slot - 1], save_slot) {
+                                let thumbnail = &save_slot.thumbnail;
+                                imgui::Image::new(
+                                    imgui::TextureId::new(texture.0.get() as usize),
+                                    [thumbnail.width as f32, thumbnail.height as f32],
+                                )
+                                .build(ui);
+                            }
+                            if ui.button("Load") {
+                                save_states::request(SlotRequest::Load(slot));
+                            }
+                            ui.same_line();
+                            if ui.button("Save") {
+                                save_states::request(SlotRequest::Save(slot));
+                            }
+                            ui.same_line();
+                            if ui.button("Delete") {
+                                save_states::request(SlotRequest::Delete(slot));
+                            }
+                        }
+                        None => {
+                            ui.text(format!("Slot {}, empty", slot));
+                            if ui.button("Save") {
+                                save_states::request(SlotRequest::Save(slot));
+                            }
+                        }
+                    }
+                    if save_slot.is_none() {
+                        if let Some((_, texture)) = thumbnails[slot - 1].take() {
+                            unsafe { gl.delete_texture(texture) };
+                        }
+                    }
+                }
+            });
+        });
+}
+
+// Uploads the thumbnail the first time we see a state, and again whenever the
+// slot has been saved over since.
+fn thumbnail_texture(
+    gl: &glow::Context,
+    cached: &mut Option<(u64, glow::Texture)>,
+    save_slot: &SaveSlot,
+) -> Option<glow::Texture> {
+    let thumbnail = &save_slot.thumbnail;
+    if let Some((saved_at, texture)) = *cached {
+        if saved_at == save_slot.saved_at {
+            return Some(texture);
+        }
+        unsafe { gl.delete_texture(texture) };
+        *cached = None;
+    }
+    if thumbnail.rgba.is_empty() {
+        return None;
+    }
+    unsafe {
+        let texture = gl.create_texture().ok()?;
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, glow::LINEAR as _);
+        gl.tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, glow::LINEAR as _);
+        gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::RGBA as _,
+            thumbnail.width as _,
+            thumbnail.height as _,
+            0,
+            glow::RGBA,
+            glow::UNSIGNED_BYTE,
+            Some(&thumbnail.rgba),
+        );
+        *cached = Some((save_slot.saved_at, texture));
+        Some(texture)
+    }
+}
+
+fn describe_age(saved_at: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or(saved_at);
+    let seconds = now.saturating_sub(saved_at) / 1000;
+    match seconds {
+        0..=59 => format!("{}s ago", seconds),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
@@ -0,0 +1,257 @@
+// Creating, duplicating and deleting entities from the editor.
+//
+// New entities always go on the end of GameState.entities, so no existing
+// index moves. Deleting one moves every entity after it down by one, and the
+// zmap is renumbered to match. Undoing a delete puts the entity back where it
+// was, on the zmap too, with insert. Members that are numbers are taken to be
+// entity indices, like the editor's z-layer panel does. Anything else the
+// game keeps by index the host can't see, so whenever entities move the host
+// calls the game library's remap_entities export, if it has one, with where
+// each of them went.
+//
+// Templates are entities saved by name from the inspector to the --templates
+// file, to create new ones from.
+use anyhow::{anyhow, bail};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::zmap;
+
+pub type Templates = BTreeMap<String, Value>;
+
+// The game library's remap_entities export. Takes an array with the new index
+// of every entity by its old index, REMOVED for one that's gone, and its
+// length. The array is only valid during the call.
+pub type GameRemapEntities = unsafe extern "C" fn(*const usize, usize);
+
+pub const REMOVED: usize = usize::MAX;
+
+// Tells the game where its entities went, unless none of them moved. Call
+// without GAME_STATE locked, the game may want to look at it.
+pub fn send_remap(remap_entities: Option<GameRemapEntities>, remap: &[usize]) {
+    if remap.iter().enumerate().all(|(old, new)| old == *new) {
+        return;
+    }
+    if let Some(remap_entities) = remap_entities {
+        unsafe { remap_entities(remap.as_ptr(), remap.len()) };
+    }
+}
+
+// A missing file is no templates yet
+pub fn load_templates(path: &Path) -> anyhow::Result<Templates> {
+    if !path.exists() {
+        return Ok(Templates::new());
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(path)?)?)
+}
+
+pub fn save_templates(path: &Path, templates: &Templates) -> anyhow::Result<()> {
+    std::fs::write(path, serde_json::to_string_pretty(templates)?)?;
+    Ok(())
+}
+
+// Adds entity to the end, on layer if there is one, and returns its index
+pub fn create<E, Z>(entities: &mut Vec<E>, zmap: &mut Z, entity: &Value, layer: Option<&str>) -> anyhow::Result<usize>
+where
+    E: DeserializeOwned,
+    Z: Serialize + DeserializeOwned,
+{
+    let entity = serde_json::from_value(entity.clone())?;
+    let index = entities.len();
+    if let Some(layer) = layer {
+        zmap::edit_layers(zmap, |layers| {
+            let Some(members) = layers.get_mut(layer).and_then(Value::as_array_mut) else {
+                bail!("there is no layer {}", layer);
+            };
+            members.push(json!(index));
+            Ok(())
+        })?;
+    }
+    entities.push(entity);
+    Ok(index)
+}
+
+// Copies entity to the end, moved by offset, right after it on its layer
+pub fn duplicate<E, Z>(entities: &mut Vec<E>, zmap: &mut Z, entity: usize, offset: [f32; 3]) -> anyhow::Result<usize>
+where
+    E: Serialize + DeserializeOwned,
+    Z: Serialize + DeserializeOwned,
+{
+    let original = entities.get(entity).ok_or_else(|| anyhow!("there is no entity {}", entity))?;
+    let mut copy = serde_json::to_value(original)?;
+    for (axis, offset) in ["x", "y", "z"].into_iter().zip(offset) {
+        if let Some(coordinate) = copy.pointer_mut(&format!("/position/{}", axis)) {
+            *coordinate = json!(coordinate.as_f64().unwrap_or(0.0) + offset as f64);
+        }
+    }
+    let copy = serde_json::from_value(copy)?;
+    let index = entities.len();
+    zmap::edit_layers(zmap, |layers| {
+        for members in layers.values_mut().filter_map(Value::as_array_mut) {
+            if let Some(position) = members.iter().position(|member| member_index(member) == Some(entity)) {
+                members.insert(position + 1, json!(index));
+                break;
+            }
+        }
+        Ok(())
+    })?;
+    entities.push(copy);
+    Ok(index)
+}
+
+// Removes entity, from the zmap too, and renumbers the members after it.
+// Returns the remap for the game.
+pub fn delete<E, Z>(entities: &mut Vec<E>, zmap: &mut Z, entity: usize) -> anyhow::Result<Vec<usize>>
+where
+    Z: Serialize + DeserializeOwned,
+{
+    if entity >= entities.len() {
+        bail!("there is no entity {}", entity);
+    }
+    zmap::edit_layers(zmap, |layers| {
+        for members in layers.values_mut().filter_map(Value::as_array_mut) {
+            members.retain(|member| member_index(member) != Some(entity));
+            for member in members.iter_mut() {
+                if let Some(index) = member_index(member).filter(|index| *index > entity) {
+                    *member = json!(index - 1);
+                }
+            }
+        }
+        Ok(())
+    })?;
+    entities.remove(entity);
+    Ok((0..=entities.len())
+        .map(|index| match index.cmp(&entity) {
+            Ordering::Less => index,
+            Ordering::Equal => REMOVED,
+            Ordering::Greater => index - 1,
+        })
+        .collect())
+}
+
+// The entity at index and where it is on the zmap, for the undo history to
+// put back with insert
+pub fn capture<E, Z>(entities: &[E], zmap: &Z, index: usize) -> anyhow::Result<Value>
+where
+    E: Serialize,
+    Z: Serialize,
+{
+    let entity = entities.get(index).ok_or_else(|| anyhow!("there is no entity {}", index))?;
+    let mut slots = vec![];
+    if let Value::Object(layers) = serde_json::to_value(zmap)? {
+        for (key, members) in layers {
+            for (position, member) in members.as_array().into_iter().flatten().enumerate() {
+                if member_index(member) == Some(index) {
+                    slots.push(json!([key, position]));
+                }
+            }
+        }
+    }
+    Ok(json!({ "entity": serde_json::to_value(entity)?, "slots": slots }))
+}
+
+// Puts an entity from capture back at index, moving every entity from there
+// on up by one, and renumbers the zmap to match. Returns the remap for the
+// game.
+pub fn insert<E, Z>(entities: &mut Vec<E>, zmap: &mut Z, index: usize, captured: &Value) -> anyhow::Result<Vec<usize>>
+where
+    E: DeserializeOwned,
+    Z: Serialize + DeserializeOwned,
+{
+    if index > entities.len() {
+        bail!("can't put an entity at {}, there are {}", index, entities.len());
+    }
+    let entity = serde_json::from_value(captured["entity"].clone())?;
+    let slots: Vec<(String, usize)> = serde_json::from_value(captured["slots"].clone())?;
+    zmap::edit_layers(zmap, |layers| {
+        for members in layers.values_mut().filter_map(Value::as_array_mut) {
+            for member in members.iter_mut() {
+                if let Some(other) = member_index(member).filter(|other| *other >= index) {
+                    *member = json!(other + 1);
+                }
+            }
+        }
+        // In the order capture found them, so earlier positions are back in
+        // place before later ones go in
+        for (layer, position) in slots {
+            let Some(members) = layers.get_mut(&layer).and_then(Value::as_array_mut) else {
+                bail!("there is no layer {}", layer);
+            };
+            members.insert(position.min(members.len()), json!(index));
+        }
+        Ok(())
+    })?;
+    let remap = (0..entities.len()).map(|other| if other < index { other } else { other + 1 }).collect();
+    entities.insert(index, entity);
+    Ok(remap)
+}
+
+fn member_index(member: &Value) -> Option<usize> {
+    member.as_u64().map(|index| index as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_renumbers_the_entities_after() {
+        let mut entities = vec![json!("a"), json!("b"), json!("c")];
+        let mut zmap = json!({ "0": [0, 2], "1": [1] });
+        let remap = delete(&mut entities, &mut zmap, 1).unwrap();
+        assert_eq!(entities, [json!("a"), json!("c")]);
+        assert_eq!(zmap, json!({ "0": [0, 1], "1": [] }));
+        assert_eq!(remap, [0, REMOVED, 1]);
+    }
+
+    #[test]
+    fn delete_of_a_missing_entity_changes_nothing() {
+        let mut entities = vec![json!("a")];
+        let mut zmap = json!({ "0": [0] });
+        assert!(delete(&mut entities, &mut zmap, 1).is_err());
+        assert_eq!(entities, [json!("a")]);
+        assert_eq!(zmap, json!({ "0": [0] }));
+    }
+
+    #[test]
+    fn insert_puts_back_what_delete_took() {
+        let mut entities = vec![json!("a"), json!("b"), json!("c")];
+        let mut zmap = json!({ "0": [0, 2], "1": [1] });
+        let captured = capture(&entities, &zmap, 1).unwrap();
+        delete(&mut entities, &mut zmap, 1).unwrap();
+        let remap = insert(&mut entities, &mut zmap, 1, &captured).unwrap();
+        assert_eq!(entities, [json!("a"), json!("b"), json!("c")]);
+        assert_eq!(zmap, json!({ "0": [0, 2], "1": [1] }));
+        assert_eq!(remap, [0, 2]);
+    }
+
+    #[test]
+    fn create_goes_on_the_end_of_its_layer() {
+        let mut entities = vec![json!("a")];
+        let mut zmap = json!({ "0": [0] });
+        assert_eq!(create(&mut entities, &mut zmap, &json!("b"), Some("0")).unwrap(), 1);
+        assert_eq!(entities, [json!("a"), json!("b")]);
+        assert_eq!(zmap, json!({ "0": [0, 1] }));
+    }
+
+    #[test]
+    fn create_on_a_missing_layer_changes_nothing() {
+        let mut entities = vec![json!("a")];
+        let mut zmap = json!({ "0": [0] });
+        assert!(create(&mut entities, &mut zmap, &json!("b"), Some("1")).is_err());
+        assert_eq!(entities, [json!("a")]);
+        assert_eq!(zmap, json!({ "0": [0] }));
+    }
+
+    #[test]
+    fn duplicate_goes_right_after_the_original() {
+        let mut entities = vec![json!({ "position": { "x": 1.0, "y": 2.0, "z": 0.0 } }), json!({})];
+        let mut zmap = json!({ "0": [0, 1] });
+        assert_eq!(duplicate(&mut entities, &mut zmap, 0, [10.0, 0.0, 0.0]).unwrap(), 2);
+        assert_eq!(entities[2], json!({ "position": { "x": 11.0, "y": 2.0, "z": 0.0 } }));
+        assert_eq!(zmap, json!({ "0": [0, 2, 1] }));
+    }
+}
@@ -0,0 +1,114 @@
+// Frame times and hitches for the editor's frame time panel.
+//
+// Every frame end_frame adds the time the whole frame took, sleep included,
+// to a rolling window. A frame whose work (everything but the sleep) didn't
+// fit in target_microseconds_per_frame counts as missed and goes into the
+// hitch log, along with anything noted during it (reloads, recordings, save
+// states) and the host phase that took longest according to the profiler.
+use engine::edit_global;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::profiler::FrameProfile;
+use crate::FRAME_STATS;
+
+pub const FRAME_TIME_COUNT: usize = 300;
+const HITCH_COUNT: usize = 100;
+
+#[derive(Debug, Clone, Default)]
+pub struct Hitch {
+    pub frame: usize,
+    pub milliseconds: f32,
+    pub work_milliseconds: f32,
+    // What was noted during the frame
+    pub notes: Vec<String>,
+    // The slowest top level profiler block and its milliseconds
+    pub slowest_block: Option<(String, f32)>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FrameStats {
+    // Milliseconds per frame, oldest first
+    pub frame_times: VecDeque<f32>,
+    pub target_milliseconds: f32,
+    pub frame_count: usize,
+    pub missed_frame_count: usize,
+    // Newest last
+    pub hitches: VecDeque<Hitch>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Summary {
+    pub min: f32,
+    pub average: f32,
+    pub p99: f32,
+    pub max: f32,
+}
+
+impl FrameStats {
+    pub fn summary(&self) -> Summary {
+        summarize(self.frame_times.iter().copied())
+    }
+}
+
+pub fn summarize(frame_times: impl Iterator<Item = f32>) -> Summary {
+    let mut sorted: Vec<f32> = frame_times.collect();
+    if sorted.is_empty() {
+        return Summary::default();
+    }
+    sorted.sort_by(f32::total_cmp);
+    Summary {
+        min: sorted[0],
+        average: sorted.iter().sum::<f32>() / sorted.len() as f32,
+        p99: percentile(&sorted, 99.0),
+        max: sorted[sorted.len() - 1],
+    }
+}
+
+// Nearest rank percentile of an already sorted, non-empty slice
+pub fn percentile(sorted: &[f32], percent: f32) -> f32 {
+    let rank = (percent / 100.0 * sorted.len() as f32).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+lazy_static! {
+    static ref NOTES: Mutex<Vec<String>> = Mutex::new(vec![]);
+}
+
+// Something that happened this frame, for the hitch log should the frame turn
+// out to be one
+pub fn note(what: &str) {
+    NOTES.lock().unwrap().push(what.to_string());
+}
+
+// Call once per frame, after profiler::end_frame
+pub fn end_frame(frame_profile: &FrameProfile, work_milliseconds: f32, target_milliseconds: f32) {
+    let notes = std::mem::take(&mut *NOTES.lock().unwrap());
+    let missed = work_milliseconds > target_milliseconds;
+    edit_global!(frame_stats, FRAME_STATS, {
+        if frame_stats.frame_times.len() >= FRAME_TIME_COUNT {
+            frame_stats.frame_times.pop_front();
+        }
+        frame_stats.frame_times.push_back(frame_profile.milliseconds);
+        frame_stats.target_milliseconds = target_milliseconds;
+        frame_stats.frame_count += 1;
+        if missed {
+            frame_stats.missed_frame_count += 1;
+            if frame_stats.hitches.len() >= HITCH_COUNT {
+                frame_stats.hitches.pop_front();
+            }
+            frame_stats.hitches.push_back(Hitch {
+                frame: frame_profile.frame,
+                milliseconds: frame_profile.milliseconds,
+                work_milliseconds,
+                notes,
+                slowest_block: frame_profile
+                    .blocks
+                    .iter()
+                    .filter(|block| block.parent.is_none())
+                    .max_by(|a, b| a.milliseconds.total_cmp(&b.milliseconds))
+                    .map(|block| (block.name.clone(), block.milliseconds)),
+            });
+        }
+    });
+}
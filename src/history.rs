@@ -0,0 +1,267 @@
+// Undo and redo for edits made from the editor.
+//
+// Every edit commands.rs applies is pushed here with what it edited, a field
+// of an entity, where a member is on the zmap, an entity that was added or
+// removed, or a tweakable, as it was before and after, serialized to JSON, so
+// undoing one is putting the before value back. Edits with the same merge key in a row become a
+// single entry, the editor uses one key per slider drag so a drag undoes in
+// one go.
+use anyhow::{anyhow, bail};
+use serde::{de::DeserializeOwned, Serialize};
+
+const HISTORY_LENGTH: usize = 200;
+
+// What an edit changed
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditTarget {
+    // The field at a JSON pointer into the serialized entity, "" for all of it.
+    // Only that field goes back on undo, whatever the game did to the rest of
+    // the entity meanwhile stays.
+    Field { entity: usize, pointer: String },
+    // A member moved between or within z-layers, before and after are where
+    // it was, see zmap::MemberPosition
+    Move(serde_json::Value),
+    // The entity at the index with where it is on the zmap, see
+    // entities::capture, or null while it doesn't exist
+    AddedOrRemoved(usize),
+    // By name, see tweaks.rs
+    Tweak(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+    pub description: String,
+    pub target: EditTarget,
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+    merge_key: Option<u64>,
+    // Unique within the history, and new again whenever an edit merges in
+    id: u64,
+}
+
+// What the editor's history panel shows
+#[derive(Debug, Clone, Default)]
+pub struct HistorySummary {
+    pub descriptions: Vec<String>,
+    // How many of the entries are applied, the rest can be redone
+    pub cursor: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    entries: Vec<HistoryEntry>,
+    cursor: usize,
+    next_id: u64,
+    // Of the newest entry dropped off the front to keep to HISTORY_LENGTH
+    dropped_id: Option<u64>,
+}
+
+impl History {
+    // Drops everything that could have been redone
+    pub fn push(
+        &mut self,
+        description: String,
+        target: EditTarget,
+        before: serde_json::Value,
+        after: serde_json::Value,
+        merge_key: Option<u64>,
+    ) {
+        self.entries.truncate(self.cursor);
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(last) = self.entries.last_mut() {
+            if merge_key.is_some() && last.merge_key == merge_key && last.target == target {
+                last.after = after;
+                last.id = id;
+                return;
+            }
+        }
+        self.entries.push(HistoryEntry {
+            description,
+            target,
+            before,
+            after,
+            merge_key,
+            id,
+        });
+        if self.entries.len() > HISTORY_LENGTH {
+            self.dropped_id = Some(self.entries.remove(0).id);
+        }
+        self.cursor = self.entries.len();
+    }
+
+    // Hands restore the entry to undo, its before value is what the target
+    // goes back to. The entry only counts as undone when restore succeeds, so
+    // a failed undo can be tried again.
+    pub fn undo<R>(&mut self, restore: impl FnOnce(&HistoryEntry) -> anyhow::Result<R>) -> anyhow::Result<R> {
+        let Some(cursor) = self.cursor.checked_sub(1) else {
+            bail!("nothing to undo");
+        };
+        let restored = restore(&self.entries[cursor])?;
+        self.cursor = cursor;
+        Ok(restored)
+    }
+
+    // Same for the entry to redo, its after value is what the target becomes
+    // again
+    pub fn redo<R>(&mut self, restore: impl FnOnce(&HistoryEntry) -> anyhow::Result<R>) -> anyhow::Result<R> {
+        let Some(entry) = self.entries.get(self.cursor) else {
+            bail!("nothing to redo");
+        };
+        let restored = restore(entry)?;
+        self.cursor += 1;
+        Ok(restored)
+    }
+
+    // Identifies the edits applied so far, the same again after undoing and
+    // redoing back to here, see scene.rs
+    pub fn position(&self) -> Option<u64> {
+        match self.cursor.checked_sub(1) {
+            Some(last_applied) => Some(self.entries[last_applied].id),
+            None => self.dropped_id,
+        }
+    }
+
+    pub fn summary(&self) -> HistorySummary {
+        HistorySummary {
+            descriptions: self.entries.iter().map(|entry| entry.description.clone()).collect(),
+            cursor: self.cursor,
+        }
+    }
+}
+
+// Applies edit to target and returns the field at pointer, "" for all of
+// target, serialized from before and after
+pub fn record_edit<T: Serialize + DeserializeOwned>(
+    target: &mut T,
+    pointer: &str,
+    edit: impl FnOnce(&mut T) -> anyhow::Result<()>,
+) -> anyhow::Result<(serde_json::Value, serde_json::Value)> {
+    let before = field(&*target, pointer)?;
+    edit(target)?;
+    let after = field(&*target, pointer)?;
+    Ok((before, after))
+}
+
+fn field<T: Serialize>(target: &T, pointer: &str) -> anyhow::Result<serde_json::Value> {
+    let value = serde_json::to_value(target)?;
+    value.pointer(pointer).cloned().ok_or_else(|| anyhow!("no field at {}", pointer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn position_of(entity: usize) -> EditTarget {
+        EditTarget::Field {
+            entity,
+            pointer: "/position".to_string(),
+        }
+    }
+
+    #[test]
+    fn edits_with_the_same_merge_key_undo_as_one() {
+        let mut history = History::default();
+        history.push("Move".into(), position_of(0), json!(0), json!(1), Some(1));
+        history.push("Move".into(), position_of(0), json!(1), json!(2), Some(1));
+        assert_eq!(history.summary().descriptions.len(), 1);
+        let entry = history.undo(|entry| Ok(entry.clone())).unwrap();
+        assert_eq!((entry.before, entry.after), (json!(0), json!(2)));
+        assert!(history.undo(|_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn edits_merge_only_on_the_same_key_and_target() {
+        let mut history = History::default();
+        history.push("Move".into(), position_of(0), json!(0), json!(1), Some(1));
+        history.push("Move".into(), position_of(1), json!(0), json!(1), Some(1));
+        history.push("Move".into(), position_of(1), json!(1), json!(2), Some(2));
+        history.push("Move".into(), position_of(1), json!(2), json!(3), None);
+        history.push("Move".into(), position_of(1), json!(3), json!(4), None);
+        assert_eq!(history.summary().descriptions.len(), 5);
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_entries() {
+        let mut history = History::default();
+        history.push("First".into(), EditTarget::Tweak("speed".into()), json!(0), json!(1), None);
+        history.push("Second".into(), EditTarget::Tweak("speed".into()), json!(1), json!(2), None);
+        assert_eq!(history.undo(|entry| Ok(entry.description.clone())).unwrap(), "Second");
+        assert_eq!(history.undo(|entry| Ok(entry.description.clone())).unwrap(), "First");
+        assert_eq!(history.summary().cursor, 0);
+        assert_eq!(history.redo(|entry| Ok(entry.after.clone())).unwrap(), json!(1));
+        assert_eq!(history.redo(|entry| Ok(entry.after.clone())).unwrap(), json!(2));
+        assert!(history.redo(|_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn pushing_drops_what_could_be_redone() {
+        let mut history = History::default();
+        history.push("First".into(), EditTarget::Tweak("speed".into()), json!(0), json!(1), None);
+        history.push("Second".into(), EditTarget::Tweak("speed".into()), json!(1), json!(2), None);
+        history.undo(|_| Ok(())).unwrap();
+        history.push("Third".into(), EditTarget::Tweak("speed".into()), json!(1), json!(3), None);
+        assert_eq!(history.summary().descriptions, vec!["First", "Third"]);
+        assert!(history.redo(|_| Ok(())).is_err());
+    }
+
+    #[test]
+    fn a_merged_edit_doesnt_merge_into_an_undone_one() {
+        let mut history = History::default();
+        history.push("Move".into(), position_of(0), json!(0), json!(1), Some(1));
+        history.undo(|_| Ok(())).unwrap();
+        history.push("Move".into(), position_of(0), json!(0), json!(5), Some(1));
+        let entry = history.undo(|entry| Ok(entry.clone())).unwrap();
+        assert_eq!((entry.before, entry.after), (json!(0), json!(5)));
+    }
+
+    #[test]
+    fn a_failed_undo_or_redo_leaves_the_cursor() {
+        let mut history = History::default();
+        history.push("First".into(), EditTarget::Tweak("speed".into()), json!(0), json!(1), None);
+        assert!(history.undo(|_| Err::<(), _>(anyhow!("gone"))).is_err());
+        assert_eq!(history.summary().cursor, 1);
+        history.undo(|_| Ok(())).unwrap();
+        assert!(history.redo(|_| Err::<(), _>(anyhow!("gone"))).is_err());
+        assert_eq!(history.summary().cursor, 0);
+        assert_eq!(history.redo(|entry| Ok(entry.after.clone())).unwrap(), json!(1));
+    }
+
+    #[test]
+    fn position_comes_back_after_undo_and_redo() {
+        let mut history = History::default();
+        assert_eq!(history.position(), None);
+        history.push("First".into(), EditTarget::Tweak("speed".into()), json!(0), json!(1), None);
+        let saved = history.position();
+        assert!(saved.is_some());
+        history.push("Second".into(), EditTarget::Tweak("speed".into()), json!(1), json!(2), None);
+        assert_ne!(history.position(), saved);
+        history.undo(|_| Ok(())).unwrap();
+        assert_eq!(history.position(), saved);
+        history.redo(|_| Ok(())).unwrap();
+        history.undo(|_| Ok(())).unwrap();
+        assert_eq!(history.position(), saved);
+    }
+
+    #[test]
+    fn position_changes_when_an_edit_merges() {
+        let mut history = History::default();
+        history.push("Move".into(), position_of(0), json!(0), json!(1), Some(1));
+        let saved = history.position();
+        history.push("Move".into(), position_of(0), json!(1), json!(2), Some(1));
+        assert_ne!(history.position(), saved);
+    }
+
+    #[test]
+    fn record_edit_keeps_only_the_field() {
+        let mut entity = json!({ "position": { "x": 1 }, "name": "a" });
+        let (before, after) = record_edit(&mut entity, "/position/x", |entity| {
+            entity["position"]["x"] = json!(2);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!((before, after), (json!(1), json!(2)));
+        assert!(record_edit(&mut entity, "/missing", |_| Ok(())).is_err());
+    }
+}
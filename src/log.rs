@@ -0,0 +1,180 @@
+// Log messages from the host and the game, for the editor's console.
+//
+// The host logs with log_debug!, log_info!, log_warn! and log_error!, which
+// take a tag saying where the message comes from and format like println!.
+// The game gets the same thing through the LogApi function table in
+// GameMemory. Every message is also printed to stdout, so headless runs still
+// show it, and the last LOG_LENGTH are kept for the console.
+use std::collections::VecDeque;
+use std::ffi::{c_char, CStr};
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+pub const LOG_LENGTH: usize = 5000;
+
+macro_rules! log_debug {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Debug, $tag, format!($($arg)*))
+    };
+}
+
+macro_rules! log_info {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Info, $tag, format!($($arg)*))
+    };
+}
+
+macro_rules! log_warn {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Warn, $tag, format!($($arg)*))
+    };
+}
+
+macro_rules! log_error {
+    ($tag:expr, $($arg:tt)*) => {
+        $crate::log::write($crate::log::Level::Error, $tag, format!($($arg)*))
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    pub const ALL: [Level; 4] = [Level::Debug, Level::Info, Level::Warn, Level::Error];
+
+    // What the game passes through the LogApi, anything past Error is Error
+    fn from_u32(level: u32) -> Self {
+        match level {
+            0 => Level::Debug,
+            1 => Level::Info,
+            2 => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LogApi {
+    // Level is 0 for debug up to 3 for error. Tag and message are C strings,
+    // a null tag logs as "game".
+    pub write: unsafe extern "C" fn(u32, *const c_char, *const c_char),
+}
+
+impl LogApi {
+    pub fn new() -> Self {
+        Self { write: log_write }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    // Counts up from 0 over the whole run, so the console can ask for what's new
+    pub sequence: u64,
+    // Seconds since the host started
+    pub time: f32,
+    pub frame: usize,
+    pub level: Level,
+    pub tag: String,
+    pub message: String,
+}
+
+impl fmt::Display for LogRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:9.3} #{:<6} {:<5} [{}] {}",
+            self.time, self.frame, self.level, self.tag, self.message
+        )
+    }
+}
+
+struct Log {
+    records: VecDeque<LogRecord>,
+    next_sequence: u64,
+    frame: usize,
+}
+
+lazy_static! {
+    static ref LOG: Mutex<Log> = Mutex::new(Log {
+        records: VecDeque::new(),
+        next_sequence: 0,
+        frame: 0,
+    });
+    static ref START: Instant = Instant::now();
+}
+
+pub fn write(level: Level, tag: &str, message: String) {
+    let mut log = LOG.lock().unwrap();
+    let record = LogRecord {
+        sequence: log.next_sequence,
+        time: START.elapsed().as_secs_f32(),
+        frame: log.frame,
+        level,
+        tag: tag.to_string(),
+        message,
+    };
+    println!("{:<5} [{}] {}", record.level, record.tag, record.message);
+    log.next_sequence += 1;
+    log.records.push_back(record);
+    if log.records.len() > LOG_LENGTH {
+        log.records.pop_front();
+    }
+}
+
+// Call at the start of every frame, messages are stamped with it
+pub fn begin_frame(frame: usize) {
+    LOG.lock().unwrap().frame = frame;
+}
+
+// Everything logged after sequence, or everything still kept with None
+pub fn records_since(sequence: Option<u64>) -> Vec<LogRecord> {
+    let log = LOG.lock().unwrap();
+    log.records
+        .iter()
+        .filter(|record| sequence.map_or(true, |sequence| record.sequence > sequence))
+        .cloned()
+        .collect()
+}
+
+pub fn export<'a>(path: &Path, records: impl IntoIterator<Item = &'a LogRecord>) -> anyhow::Result<()> {
+    let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for record in records {
+        writeln!(file, "{}", record)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+unsafe extern "C" fn log_write(level: u32, tag: *const c_char, message: *const c_char) {
+    if message.is_null() {
+        return;
+    }
+    let tag = if tag.is_null() {
+        "game".into()
+    } else {
+        CStr::from_ptr(tag).to_string_lossy()
+    };
+    let message = CStr::from_ptr(message).to_string_lossy().into_owned();
+    write(Level::from_u32(level), &tag, message);
+}
@@ -12,11 +12,10 @@ use fermium::{
     version::SDL_VERSION,
     video::{
         SDL_CreateWindow, SDL_RaiseWindow, SDL_Window, SDL_WINDOW_ALLOW_HIGHDPI, SDL_WINDOW_ALWAYS_ON_TOP,
+        SDL_WINDOW_HIDDEN,
     },
     SDL_Init, SDL_Quit, SDL_INIT_EVERYTHING,
 };
-use std::arch::asm;
-
 use windows_sys::Win32::{
     Graphics::Gdi::{GetDeviceCaps, ReleaseDC, VREFRESH},
     System::Performance::QueryPerformanceFrequency,
@@ -32,21 +31,43 @@ use windows_sys::Win32::Graphics::Gdi::GetDC;
 use windows_sys::Win32::Media::{timeBeginPeriod, TIMERR_NOERROR};
 use windows_sys::Win32::System::Performance::QueryPerformanceCounter;
 
-use tokei::{Config, Languages, LanguageType};
-
 use std::thread;
 
+#[macro_use]
+mod log;
+#[macro_use]
+mod profiler;
+mod bench;
+mod commands;
+mod console;
+mod cycles;
 mod editor;
+mod entities;
+mod frame_stats;
+mod history;
 mod imgui_backend;
+mod memory;
+mod overlay;
+mod desync;
+mod recording;
+mod replay;
+mod rewind;
+mod save_states;
+mod scene;
+mod stats;
+mod trace;
+mod tweaks;
+mod zmap;
 
 #[macro_use]
 extern crate lazy_static;
 
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
 #[macro_use]
 extern crate maplit;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use std::{isize, path::PathBuf};
 
 //const PI32: f32 = 3.14159265359;
@@ -65,20 +86,46 @@ const FOCUS_LOST: u8 = fermium::video::SDL_WINDOWEVENT_FOCUS_LOST.0;
 
 type GameUpdateCallback<'a> = libloading::Symbol<
     'a,
-    unsafe extern "C" fn(*mut SDL_Renderer, GameStateArc, GameInputArc) -> bool,
+    unsafe extern "C" fn(*mut SDL_Renderer, GameStateArc, GameInputArc, *mut memory::GameMemory) -> bool,
+>;
+type GameInitCallback<'a> = libloading::Symbol<
+    'a,
+    unsafe extern "C" fn(Arc<Mutex<GameState>>, *mut memory::GameMemory) -> bool,
 >;
-type GameInitCallback<'a> =
-    libloading::Symbol<'a, unsafe extern "C" fn(Arc<Mutex<GameState>>) -> bool>;
 type GameInputCallback<'a> =
     libloading::Symbol<'a, unsafe extern "C" fn(SDL_Event) -> engine::GameInput>;
-// Keeps track of input and the time offset from the recording start
-type RecordedInput = Vec<(u128, engine::GameInput)>;
+type GameConsoleCallback<'a> = libloading::Symbol<'a, console::GameConsoleCommand>;
+type GameRemapCallback<'a> = libloading::Symbol<'a, entities::GameRemapEntities>;
+// Keeps track of input by the simulation frame it was fed to the game on,
+// the index into the vec is the frame index since the recording start.
+type RecordedInput = Vec<RecordedFrame>;
 type RecordedInputArc = Arc<Mutex<RecordedInput>>;
 
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct RecordedFrame {
+    // Exactly what the game saw this frame, elapsed included, so playback
+    // doesn't depend on the frame rate it runs at
+    timing_info: engine::TimingInfo,
+    // In the order the game received them within the frame
+    inputs: Vec<engine::GameInput>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+struct StateCheckpoint {
+    frame: usize,
+    hash: u64,
+    game_state: GameState,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 struct RecordedGame {
     game_state_at_start: GameState,
     recorded_input: RecordedInput,
+    // The state after the game updated on every --checkpoint-interval frame,
+    // playback checks against these to catch desyncs
+    checkpoints: Vec<StateCheckpoint>,
+    // As they were when the recording started, see tweaks.rs
+    tweaks: tweaks::TweakValues,
 }
 
 lazy_static! {
@@ -108,7 +155,19 @@ lazy_static! {
     }));
     static ref GAME_INPUT: GameInputArc = Arc::new(Mutex::new(vec![]));
     static ref RECORDED_INPUT: RecordedInputArc = Arc::new(Mutex::new(vec![]));
-    static ref RECORDING_START: Arc<Mutex<Option<std::time::Instant>>> = Arc::new(Mutex::new(None));
+    static ref RECORDED_CHECKPOINTS: Arc<Mutex<Vec<StateCheckpoint>>> = Arc::new(Mutex::new(vec![]));
+    static ref RECORDING: Arc<Mutex<bool>> = Arc::new(Mutex::new(false));
+    static ref SAVE_SLOTS: Arc<Mutex<Vec<Option<save_states::SaveSlot>>>> =
+        Arc::new(Mutex::new(vec![None; save_states::SLOT_COUNT]));
+    static ref SAVE_SLOT_REQUESTS: Arc<Mutex<Vec<save_states::SlotRequest>>> = Arc::new(Mutex::new(vec![]));
+    static ref REWIND_REQUESTS: Arc<Mutex<Vec<rewind::RewindRequest>>> = Arc::new(Mutex::new(vec![]));
+    static ref REWIND_STATUS: Arc<Mutex<rewind::RewindStatus>> = Arc::new(Mutex::new(Default::default()));
+    static ref EDITOR_COMMANDS: Arc<Mutex<Vec<commands::EditorCommand>>> = Arc::new(Mutex::new(vec![]));
+    static ref EDITOR_SNAPSHOT: Arc<Mutex<Arc<commands::EditorSnapshot>>> = Arc::new(Mutex::new(Default::default()));
+    static ref MEMORY_STATS: Arc<Mutex<Vec<memory::ArenaUsage>>> = Arc::new(Mutex::new(vec![]));
+    // The last profiler::PROFILE_FRAME_COUNT frames, oldest first
+    static ref PROFILE_FRAMES: Arc<Mutex<VecDeque<profiler::FrameProfile>>> = Arc::new(Mutex::new(VecDeque::new()));
+    static ref FRAME_STATS: Arc<Mutex<frame_stats::FrameStats>> = Arc::new(Mutex::new(frame_stats::FrameStats::default()));
 }
 
 fn kilobytes(v: usize) -> usize {
@@ -120,24 +179,26 @@ fn megabytes(v: usize) -> usize {
 }
 
 fn gigabytes(v: usize) -> usize {
-    kilobytes(v) * KILOBITS
+    megabytes(v) * KILOBITS
 }
 
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-#[inline]
-fn rdtsc() -> u64 {
-    let mut reg_eax: u32;
-    let mut reg_edx: u32;
-
-    unsafe {
-        asm!("rdtsc", out("eax") reg_eax, out("edx") reg_edx);
-    }
+fn terabytes(v: usize) -> usize {
+    gigabytes(v) * KILOBITS
+}
 
-    (reg_edx as u64) << 32 | reg_eax as u64
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    // Count lines of code in the project instead of running the game
+    Stats(stats::StatsArgs),
+    // Time the game headless and compare against a baseline
+    Bench(bench::BenchArgs),
 }
+
 #[derive(Parser, Debug, Clone)]
 #[command(version, about, long_about = None)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
     #[arg(long)]
     slow: bool,
     #[arg(long)]
@@ -146,12 +207,48 @@ struct Cli {
     width: i32,
     #[arg(long, default_value = "768")]
     height: i32,
+    // In megabytes
     #[arg(long, default_value = "64")]
     permanent_memory_size: usize,
+    // In megabytes
     #[arg(long, default_value = "128")]
     transient_memory_size: usize,
+    // Put game memory at the same address on every run
+    #[arg(long)]
+    fixed_memory_base: bool,
     #[arg(long, default_value = "../game/target/debug/game.dll")]
     game_dll: PathBuf,
+    // Record the session from startup and write it to this file
+    #[arg(long)]
+    record: Option<PathBuf>,
+    // Load a file written by --record and loop it
+    #[arg(long)]
+    replay: Option<PathBuf>,
+    // Store a state hash every this many frames while recording
+    #[arg(long, default_value = "60")]
+    checkpoint_interval: usize,
+    // Play the --replay once, check it against its state hashes and exit
+    // non-zero on a desync
+    #[arg(long, requires = "replay")]
+    verify: bool,
+    // No editor, a hidden game window and no frame rate limit
+    #[arg(long)]
+    headless: bool,
+    // Megabytes of state history to keep for rewinding, 0 turns rewind off
+    #[arg(long, default_value = "64")]
+    rewind_budget: usize,
+    // Write a Chrome trace from startup to this file, F11 toggles it
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    // Tweaked values are saved to this file and loaded from it at startup
+    #[arg(long, default_value = "tuning.json")]
+    tuning: PathBuf,
+    // Load this scene once the game has initialized
+    #[arg(long)]
+    scene: Option<PathBuf>,
+    // Entity templates the editor creates entities from, and saves them to
+    #[arg(long, default_value = "entity_templates.json")]
+    templates: PathBuf,
 }
 
 #[derive(Debug, Clone, Default)]
@@ -169,6 +266,7 @@ struct TimingInfo {
     elapsed: f32,
     cycles_elapsed: i64,
     work_counter: i64,
+    cycle_counter: cycles::CycleCounter,
 }
 
 impl TimingInfo {
@@ -177,12 +275,8 @@ impl TimingInfo {
             (self.work_counter - self.last_counter) as f32 / self.performance_count_frequency as f32
     }
     fn update(&mut self) {
-        self.current_cycle_count = rdtsc();
-        self.cycles_elapsed = self.current_cycle_count as i64 - self.last_cycle_count as i64;
-        if self.cycles_elapsed < 0 {
-            // This happens sometimes because, like I don't know...
-            self.cycles_elapsed = 1;
-        }
+        self.cycles_elapsed = self.cycle_counter.elapsed() as i64;
+        self.current_cycle_count = self.cycle_counter.last().count;
         self.megacycles_per_frame = (self.cycles_elapsed as f32) / (1000.0 * 1000.0); // megacylces per frame
         self.last_cycle_count = self.current_cycle_count;
 
@@ -243,21 +337,33 @@ pub struct Platform {
     renderer: *mut SDL_Renderer,
     cli: Cli,
     timing_info: TimingInfo,
-}
-
-fn print_project_stats() -> () {
-    // The paths to search. Accepts absolute, relative, and glob paths.
-    let paths = &["src", "../game/src", "../engine/src"];
-    // Exclude any path that contains any of these strings.
-    let excluded = &["target"];
-    // `Config` allows you to configure what is searched and counted.
-    let config = Config::default();
-
-    let mut languages = Languages::new();
-    languages.get_statistics(paths, excluded, &config);
-    let rust = &languages[&LanguageType::Rust];
-
-    println!("Lines of code: {}", rust.code);
+    playback: Option<recording::Playback>,
+    // Of the copy of the game library we actually loaded
+    game_library_hash: u64,
+    rewind: rewind::RewindBuffer,
+    memory: memory::GameMemory,
+    // Of the permanent arena when the running recording started
+    recording_memory: Option<Arc<memory::MemorySnapshot>>,
+    // And the tweak values
+    recording_tweaks: tweaks::TweakValues,
+    // Per save state slot, index 0 is slot 1
+    slot_memory: Vec<Option<Arc<memory::MemorySnapshot>>>,
+    paused: bool,
+    history: history::History,
+    // Reload the game library at the end of the frame even if it hasn't changed
+    reload_requested: bool,
+    layer_visibility: zmap::LayerVisibility,
+    // Frames to let through while paused, see the console's step command
+    step_frames: usize,
+    // Scales the elapsed time the game sees, see the console's timescale command
+    time_scale: f32,
+    // The scene last saved or loaded, and the history position it was at
+    scene_path: Option<PathBuf>,
+    saved_position: Option<u64>,
+    templates: entities::Templates,
+    // The game library's remap_entities export, if it has one. Looked up
+    // again on every reload.
+    remap_entities: Option<entities::GameRemapEntities>,
 }
 
 fn get_hwnd(window: *mut SDL_Window) -> Option<isize> {
@@ -290,7 +396,7 @@ fn set_layered_attributes(platform: &Platform, color_key: COLORREF, alpha: u8) -
                         | WS_EX_TOPMOST as isize,
                 );
                 if result == 0 {
-                    println!("Failed to SetWindowLongA");
+                    log_warn!("window", "Failed to SetWindowLongA");
                 }
                 let result = SetLayeredWindowAttributes(hwnd, color_key, alpha, LWA_ALPHA);
                 SDL_RaiseWindow(platform.window);
@@ -329,32 +435,45 @@ fn handle_sdl_events(platform: &mut Platform, event: SDL_Event, callback: &GameI
                 // SDL_QUIT
                 platform.running = false;
             }
-            KEYDOWN => {
-                // SDL_KEYDOWN
-                let input = callback(event.clone());
-                edit_global!(game_input, GAME_INPUT, {
-                    game_input.push(input.clone());
-                    access_global!(recording, RECORDING_START, {
-                        if let Some(instant) = recording {
-                            edit_global!(recorded_input, RECORDED_INPUT, {
-                                recorded_input.push((instant.elapsed().as_millis(), input.clone()));
-                            });
-                        }
-                    });
-                });
+            KEYDOWN if recording::is_hotkey(event.key.keysym.sym) => {
+                if event.key.repeat == 0 {
+                    recording::handle_hotkey(platform, event.key.keysym.sym);
+                }
+            }
+            KEYUP if recording::is_hotkey(event.key.keysym.sym) => {}
+            KEYDOWN if save_states::hotkey_request(&event.key.keysym).is_some() => {
+                if event.key.repeat == 0 {
+                    save_states::request(save_states::hotkey_request(&event.key.keysym).unwrap());
+                }
+            }
+            KEYUP if save_states::hotkey_request(&event.key.keysym).is_some() => {}
+            KEYDOWN if rewind::hotkey_request(event.key.keysym.sym).is_some() => {
+                // Holding the step keys down keeps stepping
+                rewind::request(rewind::hotkey_request(event.key.keysym.sym).unwrap());
+            }
+            KEYUP if rewind::hotkey_request(event.key.keysym.sym).is_some() => {}
+            KEYDOWN if event.key.keysym.sym == trace::TOGGLE_KEY => {
+                if event.key.repeat == 0 {
+                    trace::toggle();
+                }
             }
-            KEYUP => {
-                // SDL_KEYUP
+            KEYUP if event.key.keysym.sym == trace::TOGGLE_KEY => {}
+            KEYDOWN if event.key.keysym.sym == overlay::TOGGLE_KEY => {
+                if event.key.repeat == 0 {
+                    overlay::cycle_mode();
+                }
+            }
+            KEYUP if event.key.keysym.sym == overlay::TOGGLE_KEY => {}
+            KEYDOWN | KEYUP => {
+                // SDL_KEYDOWN, SDL_KEYUP
+                // While a recording loops the game only sees the recorded input
+                if platform.playback.is_some() {
+                    return;
+                }
                 let input = callback(event.clone());
+                recording::record_input(&input);
                 edit_global!(game_input, GAME_INPUT, {
-                    game_input.push(input.clone());
-                    access_global!(recording, RECORDING_START, {
-                        if let Some(instant) = recording {
-                            edit_global!(recorded_input, RECORDED_INPUT, {
-                                recorded_input.push((instant.elapsed().as_millis(), input.clone()));
-                            });
-                        }
-                    });
+                    game_input.push(input);
                 });
             }
             WINDOWEVENT => {
@@ -365,13 +484,13 @@ fn handle_sdl_events(platform: &mut Platform, event: SDL_Event, callback: &GameI
                     // SDL_WINDOW_EVENT_FOCUS_GAINED
                     FOCUS_GAINED => {
                         if !set_layered_attributes(platform, 0, 255) {
-                            println!("failed to set layered attributes on focus gained");
+                            log_warn!("window", "Failed to set layered attributes on focus gained");
                         }
                     }
                     // SDL_WINDOW_EVENT_FOCUS_LOST
                     FOCUS_LOST => {
                         if !set_layered_attributes(platform, 0, 64) {
-                            println!("Failed to set layered attributes on loss of focus");
+                            log_warn!("window", "Failed to set layered attributes on loss of focus");
                         }
                     }
                     _ => {}
@@ -383,14 +502,43 @@ fn handle_sdl_events(platform: &mut Platform, event: SDL_Event, callback: &GameI
 }
 
 fn main() {
-    let cli = Cli::parse();
-    print_project_stats();
-    
+    let mut cli = Cli::parse();
+    if let Some(Command::Stats(args)) = cli.command.as_ref() {
+        if let Err(error) = stats::run(args) {
+            eprintln!("Failed to gather project stats: {:?}", error);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut bench = match cli.command.clone() {
+        Some(Command::Bench(args)) => {
+            // A benchmark is a headless run of the replay, if there is one
+            cli.headless = true;
+            cli.replay = args.replay.clone();
+            Some(bench::Bench::new(args))
+        }
+        _ => None,
+    };
+    trace::name_thread("main");
+    trace::set_path(cli.trace.clone());
+    if let Some(path) = cli.trace.as_ref() {
+        if let Err(error) = trace::start(path) {
+            log_error!("trace", "Failed to start tracing to {}: {:?}", path.display(), error);
+        }
+    }
     unsafe {
         assert_eq!(SDL_Init(SDL_INIT_EVERYTHING), 0);
-        let editor_handler = thread::spawn(|| {
-            editor::spawn_window();
-        });
+        let editor_handler = if cli.headless {
+            None
+        } else {
+            Some(thread::spawn(|| {
+                editor::spawn_window();
+            }))
+        };
+        let mut window_flags = SDL_WINDOW_ALLOW_HIGHDPI | SDL_WINDOW_ALWAYS_ON_TOP;
+        if cli.headless {
+            window_flags = window_flags | SDL_WINDOW_HIDDEN;
+        }
 
         let window = SDL_CreateWindow(
             b"Circuit Mage\0".as_ptr().cast(),
@@ -399,7 +547,7 @@ fn main() {
             cli.width,
             cli.height,
             // The following is key for the transparent window trick to work
-            (SDL_WINDOW_ALLOW_HIGHDPI | SDL_WINDOW_ALWAYS_ON_TOP).0,
+            window_flags.0,
         );
         // Panic if window is not null
         assert!(!window.is_null());
@@ -415,6 +563,31 @@ fn main() {
             timing_info: TimingInfo::new(get_hwnd(window)),
             renderer,
             cli: cli.clone(),
+            playback: None,
+            game_library_hash: 0,
+            rewind: rewind::RewindBuffer::new(megabytes(cli.rewind_budget)),
+            memory: memory::reserve(
+                megabytes(cli.permanent_memory_size),
+                megabytes(cli.transient_memory_size),
+                cli.fixed_memory_base,
+            )
+            .expect("Failed to reserve game memory"),
+            recording_memory: None,
+            recording_tweaks: tweaks::TweakValues::new(),
+            slot_memory: vec![None; save_states::SLOT_COUNT],
+            paused: false,
+            history: history::History::default(),
+            reload_requested: false,
+            layer_visibility: zmap::LayerVisibility::default(),
+            step_frames: 0,
+            time_scale: 1.0,
+            scene_path: None,
+            saved_position: None,
+            templates: entities::load_templates(&cli.templates).unwrap_or_else(|error| {
+                log_error!("entities", "Failed to load {}: {:?}", cli.templates.display(), error);
+                entities::Templates::new()
+            }),
+            remap_entities: None,
         };
         edit_global!(game_state, GAME_STATE, {
             game_state.window.width = cli.width as usize;
@@ -428,9 +601,18 @@ fn main() {
         
         std::fs::copy(dll_source, dll_dest.clone()).unwrap();
         let mut lib = libloading::Library::new(dll_dest.clone()).unwrap();
+        if let Err(error) = memory::check_version(&lib) {
+            log_error!("memory", "Refusing to run {}: {:?}", dll_source.to_string_lossy(), error);
+            std::process::exit(1);
+        }
+        platform.game_library_hash = replay::hash_file(std::path::Path::new(&dll_dest)).unwrap();
         let mut game: GameUpdateCallback = lib.get("update_and_render".as_bytes()).unwrap();
         let game_init: GameInitCallback = lib.get("init".as_bytes()).unwrap(); // we don't reload this
         let mut game_decide_input: GameInputCallback = lib.get("decide_input".as_bytes()).unwrap();
+        // Only games with console commands of their own export this
+        let mut game_console_command: Option<GameConsoleCallback> = lib.get("console_command".as_bytes()).ok();
+        let remap_entities: Option<GameRemapCallback> = lib.get("remap_entities".as_bytes()).ok();
+        platform.remap_entities = remap_entities.map(|remap_entities| *remap_entities);
         let mut dll_modified_time = std::fs::metadata(dll_source)
             .unwrap()
             .modified()
@@ -441,59 +623,157 @@ fn main() {
 
         let mut event = SDL_Event::default();
 
-        platform.timing_info.last_cycle_count = rdtsc();
+        platform.timing_info.cycle_counter.restart();
+        platform.timing_info.last_cycle_count = platform.timing_info.cycle_counter.last().count;
         platform.timing_info.last_counter = get_wall_clock();
         platform.timing_info.loop_counter = 0;
         platform.timing_info.update();
         edit_global!(game_state, GAME_STATE, {
             game_state.timing_info = platform.timing_info.clone().into();
         });
-        game_init(Arc::clone(&GAME_STATE));
+        if let Err(error) = tweaks::load(&cli.tuning) {
+            log_error!("tweaks", "Failed to load {}: {:?}", cli.tuning.display(), error);
+        }
+        game_init(Arc::clone(&GAME_STATE), &mut platform.memory);
+        if let Some(path) = cli.scene.as_ref() {
+            let load_scene = commands::EditorCommand::LoadScene(path.clone());
+            if let Err(error) = commands::apply_command(&mut platform, &load_scene) {
+                log_error!("scene", "Failed to load {}: {:?}", path.display(), error);
+                std::process::exit(1);
+            }
+        }
+        save_states::load_all(&mut platform);
+        if let Some(path) = cli.replay.as_ref() {
+            match replay::load(path, &platform) {
+                Ok(recorded_game) => {
+                    let snapshot_path = replay::memory_snapshot_path(path);
+                    let memory_snapshot = if snapshot_path.exists() {
+                        match memory::MemorySnapshot::open(&platform.memory, &snapshot_path) {
+                            Ok(memory_snapshot) => Some(Arc::new(memory_snapshot)),
+                            Err(error) => {
+                                log_error!("replay", "Refusing to play {}: {:?}", path.display(), error);
+                                std::process::exit(1);
+                            }
+                        }
+                    } else {
+                        None
+                    };
+                    recording::start_playback(&mut platform, recorded_game, memory_snapshot);
+                }
+                Err(error) => {
+                    log_error!("replay", "Refusing to play {}: {:?}", path.display(), error);
+                    std::process::exit(1);
+                }
+            }
+        } else if cli.record.is_some() {
+            recording::start_recording(&mut platform);
+        }
 
+        let mut frame_index = 0;
         while platform.running {
-            while SDL_PollEvent(&mut event) == 1 {
-                handle_sdl_events(&mut platform, event.clone(), &game_decide_input);
+            log::begin_frame(frame_index);
+            {
+                timed_block!("event poll");
+                while SDL_PollEvent(&mut event) == 1 {
+                    handle_sdl_events(&mut platform, event.clone(), &game_decide_input);
+                }
             }
-            SDL_RenderClear(platform.renderer);
-            game(
-                platform.renderer,
-                Arc::clone(&GAME_STATE),
-                Arc::clone(&GAME_INPUT),
-            );
-            SDL_RenderPresent(platform.renderer);
-            let new_dll_modified_time = std::fs::metadata(dll_source)
-                .unwrap()
-                .modified()
-                .unwrap()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            if new_dll_modified_time > dll_modified_time {
-                lib.close().unwrap();
-                std::fs::copy(dll_source, dll_dest.clone()).unwrap();
-                lib = libloading::Library::new(dll_dest.clone()).unwrap();
-                game = lib.get("update_and_render".as_bytes()).unwrap();
-                game_decide_input = lib.get("decide_input".as_bytes()).unwrap();
-                dll_modified_time = new_dll_modified_time;
+            {
+                timed_block!("update");
+                commands::apply(&mut platform);
+                console::handle_requests(&mut platform, game_console_command.as_ref().map(|command| **command));
+                // A paused replay stays on its frame until it's stepped
+                if !commands::is_frozen(&platform) {
+                    if let Some(playback) = platform.playback.as_mut() {
+                        playback.update(&mut platform.memory);
+                    }
+                }
+                recording::end_input_frame();
+                rewind::before_game_update(&mut platform);
+                let frozen = commands::freeze_if_paused(&mut platform);
+                memory::begin_frame(&mut platform.memory);
+                SDL_RenderClear(platform.renderer);
+                {
+                    timed_block!("game");
+                    game(
+                        platform.renderer,
+                        Arc::clone(&GAME_STATE),
+                        Arc::clone(&GAME_INPUT),
+                        &mut platform.memory,
+                    );
+                }
+                recording::after_game_update(&mut platform);
+                rewind::after_game_update(&mut platform, frozen);
+                memory::publish_stats(&platform.memory);
+                save_states::handle_requests(&mut platform);
+                // Copies the whole state, only worth it with someone to look at it
+                if editor_handler.is_some() {
+                    commands::publish_snapshot(&platform, frame_index);
+                }
+            }
+            {
+                timed_block!("present");
+                overlay::render(platform.renderer);
+                SDL_RenderPresent(platform.renderer);
+            }
+            {
+                timed_block!("reload check");
+                let new_dll_modified_time = std::fs::metadata(dll_source)
+                    .unwrap()
+                    .modified()
+                    .unwrap()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                if new_dll_modified_time > dll_modified_time || platform.reload_requested {
+                    platform.reload_requested = false;
+                    trace::marker("reload");
+                    lib.close().unwrap();
+                    std::fs::copy(dll_source, dll_dest.clone()).unwrap();
+                    lib = libloading::Library::new(dll_dest.clone()).unwrap();
+                    if let Err(error) = memory::check_version(&lib) {
+                        log_error!("memory", "Refusing to run {}: {:?}", dll_source.to_string_lossy(), error);
+                        std::process::exit(1);
+                    }
+                    platform.game_library_hash = replay::hash_file(std::path::Path::new(&dll_dest)).unwrap();
+                    game = lib.get("update_and_render".as_bytes()).unwrap();
+                    game_decide_input = lib.get("decide_input".as_bytes()).unwrap();
+                    game_console_command = lib.get("console_command".as_bytes()).ok();
+                    let remap_entities: Option<GameRemapCallback> = lib.get("remap_entities".as_bytes()).ok();
+                    platform.remap_entities = remap_entities.map(|remap_entities| *remap_entities);
+                    dll_modified_time = new_dll_modified_time;
+                }
             }
             platform.timing_info.update();
+            // The sleep below keeps updating the timing info
+            let work_milliseconds = platform.timing_info.milliseconds_per_frame;
             // This will always show as the previous frame in the output, not the current frame, because we aren't done with it.
             if platform.timing_info.loop_counter % 10 == 0 {
-                edit_global!(game_state, GAME_STATE, {
-                    game_state.texts[1] = Some(format!(
-                        "{:.2}ms/f,  {:.1}f/s,  {:.2}mc/f, z={:.2}",
+                overlay::set_line(
+                    "host",
+                    "frame",
+                    format!(
+                        "{:.2}ms/f,  {:.1}f/s,  {:.2}mc/f",
                         platform.timing_info.milliseconds_per_frame,
                         platform.timing_info.fps,
-                        platform.timing_info.megacycles_per_frame,
-                        game_state.entities[0].position.z
-                    ));
+                        platform.timing_info.megacycles_per_frame
+                    ),
+                );
+                access_global!(game_state, GAME_STATE, {
+                    overlay::set_line("host", "entities", game_state.entities.len().to_string());
+                    match game_state.entities.first() {
+                        Some(entity) => overlay::set_line("host", "z", format!("{:.2}", entity.position.z)),
+                        None => overlay::remove_line("host", "z"),
+                    }
                 });
             }
 
-            //platform.game_state.texts[1] = Some("1.345".to_string());
             // We are targeting here at least 60fps, so generally we expect each frame to take 16ms But we are okay with rendering
             // at a higher framerate,
-            if platform.timing_info.elapsed < platform.timing_info.target_microseconds_per_frame {
+            if !cli.headless
+                && platform.timing_info.elapsed < platform.timing_info.target_microseconds_per_frame
+            {
+                timed_block!("sleep");
                 if platform.timing_info.sleep_is_granular {
                     let mut sleep_milliseconds = 1000.0
                         * (platform.timing_info.target_microseconds_per_frame
@@ -512,6 +792,19 @@ fn main() {
                     }
                 }
             }
+            let frame_profile = profiler::end_frame(frame_index);
+            frame_stats::end_frame(
+                &frame_profile,
+                work_milliseconds,
+                1000.0 * platform.timing_info.target_microseconds_per_frame,
+            );
+            if let Some(bench) = bench.as_mut() {
+                bench.record(&frame_profile);
+                if bench.is_done() {
+                    platform.running = false;
+                }
+            }
+            frame_index += 1;
             platform.timing_info.last_counter = get_wall_clock();
             platform.timing_info.loop_counter += 1;
             if platform.timing_info.loop_counter > 1000 {
@@ -519,10 +812,26 @@ fn main() {
             }
             edit_global!(game_state, GAME_STATE, {
                 game_state.timing_info = platform.timing_info.clone().into();
+                game_state.timing_info.elapsed *= platform.time_scale;
             });
         }
-        editor_handler.join().unwrap();
+        if recording::is_recording() {
+            recording::stop_recording(&platform);
+        }
+        let verify_failed = cli.verify
+            && platform
+                .playback
+                .as_ref()
+                .map_or(true, |playback| playback.first_desync().is_some());
+        let bench_failed = bench.as_ref().map_or(false, |bench| !bench.report());
+        if let Some(editor_handler) = editor_handler {
+            editor_handler.join().unwrap();
+        }
+        trace::stop();
         SDL_DestroyWindow(platform.window);
         SDL_Quit();
+        if verify_failed || bench_failed {
+            std::process::exit(1);
+        }
     }
 }
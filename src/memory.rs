@@ -0,0 +1,603 @@
+// Game memory, one contiguous block the host reserves up front and splits in
+// a permanent and a transient part.
+//
+// The game gets a GameMemory through init and update_and_render and is
+// expected to keep everything it can't rebuild in permanent storage, so a
+// reload or a snapshot of the block gives back the exact same game. The
+// transient part is scratch space, the host resets it at the start of every
+// frame.
+//
+// Both parts come wrapped in a MemoryArena, and the game allocates from them
+// through the ArenaApi function table rather than bumping pointers itself.
+// That way the host sees every allocation: it keeps high water marks and
+// per-tag usage for the editor's memory panel, and stops the game with a
+// clear message when an arena overflows instead of letting it scribble past
+// the end. That's an abort rather than a panic, the ArenaApi functions are
+// called from the game library and a panic can't unwind back into it, and
+// begin_frame does the same for consistency. The per-tag usage lives in a
+// fixed array in the arena itself, so pushing takes no lock and allocates
+// nothing, and putting an arena back puts its usage back with it.
+//
+// MemorySnapshot copies the used part of the permanent arena, for recordings
+// and save states. The rewind buffer keeps its own copies, see rewind.rs.
+//
+// Everything the game sees is repr(C) because the game library has to declare
+// the same structs on its side of the boundary. The game library exports
+// memory_version, returning the GAME_MEMORY_VERSION it was built against, and
+// the host refuses to call into one that doesn't match.
+use anyhow::{bail, Context};
+use engine::edit_global;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CStr};
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use windows_sys::Win32::Foundation::{CloseHandle, HANDLE};
+use windows_sys::Win32::System::Memory::{
+    CreateFileMappingA, MapViewOfFile, UnmapViewOfFile, VirtualAlloc, FILE_MAP_ALL_ACCESS,
+    MEMORY_MAPPED_VIEW_ADDRESS, MEM_COMMIT, MEM_RESERVE, PAGE_READWRITE,
+};
+
+use crate::console::ConsoleApi;
+use crate::log::LogApi;
+use crate::overlay::OverlayApi;
+use crate::profiler::ProfilerApi;
+use crate::tweaks::TweakApi;
+use crate::zmap::LayerApi;
+use crate::{terabytes, MEMORY_STATS};
+
+// Bump whenever GameMemory or anything in it changes layout
+pub const GAME_MEMORY_VERSION: u32 = 1;
+
+// The game library's memory_version export
+pub type GameMemoryVersion = unsafe extern "C" fn() -> u32;
+
+// How many tags an arena keeps usage for, past that the last one becomes
+// "other" and counts the rest, and how much of a tag's name is kept
+pub const ARENA_TAG_COUNT: usize = 16;
+pub const ARENA_TAG_NAME_SIZE: usize = 24;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArenaTag {
+    // Copied rather than pointed to, tags are usually string literals in the
+    // game library and those go away when it reloads. Zero padded.
+    pub name: [u8; ARENA_TAG_NAME_SIZE],
+    // In use, padding included
+    pub bytes: usize,
+}
+
+// Kept by the host, the game only carries it around
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArenaTags {
+    pub count: u32,
+    pub tags: [ArenaTag; ARENA_TAG_COUNT],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArena {
+    // Static C string, for messages and the memory panel
+    pub name: *const c_char,
+    pub base: *mut u8,
+    pub size: usize,
+    pub used: usize,
+    pub high_water_mark: usize,
+    // Open TemporaryMemory scopes
+    pub temporary_count: u32,
+    pub tags: ArenaTags,
+}
+
+// What end_temporary rolls the arena back to
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TemporaryMemory {
+    pub arena: *mut MemoryArena,
+    pub used: usize,
+    pub tags: ArenaTags,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ArenaApi {
+    // Returns size bytes aligned to align, which must be a power of two. The
+    // tag is a C string naming what the memory is for, it may be null.
+    pub push: unsafe extern "C" fn(*mut MemoryArena, usize, usize, *const c_char) -> *mut u8,
+    pub begin_temporary: unsafe extern "C" fn(*mut MemoryArena) -> TemporaryMemory,
+    pub end_temporary: unsafe extern "C" fn(TemporaryMemory),
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct GameMemory {
+    // GAME_MEMORY_VERSION and the size of this struct, so the game can check
+    // it was handed what it was built against too
+    pub version: u32,
+    pub size: u32,
+    pub permanent_storage_size: usize,
+    // Zeroed when the host hands it over for the first time
+    pub permanent_storage: *mut u8,
+    pub transient_storage_size: usize,
+    pub transient_storage: *mut u8,
+    pub permanent_arena: MemoryArena,
+    pub transient_arena: MemoryArena,
+    pub arena_api: ArenaApi,
+    // Not memory, but this is what the game gets handed every frame
+    pub profiler_api: ProfilerApi,
+    pub overlay_api: OverlayApi,
+    pub log_api: LogApi,
+    pub console_api: ConsoleApi,
+    pub tweak_api: TweakApi,
+    pub layer_api: LayerApi,
+}
+
+// Usage of one arena, published for the editor after every frame
+#[derive(Debug, Clone, Default)]
+pub struct ArenaUsage {
+    pub name: String,
+    pub size: usize,
+    pub used: usize,
+    pub high_water_mark: usize,
+    // Bytes currently in use per tag, padding included
+    pub tags: BTreeMap<String, usize>,
+}
+
+// Call with every game library before calling into it
+pub fn check_version(lib: &libloading::Library) -> anyhow::Result<()> {
+    let memory_version: libloading::Symbol<GameMemoryVersion> = unsafe { lib.get("memory_version".as_bytes()) }
+        .context("the game library doesn't export memory_version, it predates GAME_MEMORY_VERSION")?;
+    let version = unsafe { memory_version() };
+    if version != GAME_MEMORY_VERSION {
+        bail!(
+            "the game library was built against GameMemory version {}, this host hands over version {}",
+            version,
+            GAME_MEMORY_VERSION
+        );
+    }
+    Ok(())
+}
+
+// Reserves and commits the whole block in one go. With fixed_base the block
+// sits at the same address on every run, so pointers the game keeps in it
+// stay valid across reloads, recordings and snapshots written to disk.
+//
+// The block lives as long as the process, there is no point handing it back.
+pub fn reserve(
+    permanent_storage_size: usize,
+    transient_storage_size: usize,
+    fixed_base: bool,
+) -> anyhow::Result<GameMemory> {
+    let total_size = permanent_storage_size + transient_storage_size;
+    let base_address = if fixed_base {
+        fixed_base_address()
+    } else {
+        0
+    };
+    let block = unsafe {
+        VirtualAlloc(
+            base_address as *const c_void,
+            total_size,
+            MEM_RESERVE | MEM_COMMIT,
+            PAGE_READWRITE,
+        )
+    };
+    if block.is_null() {
+        bail!(
+            "couldn't reserve {} bytes of game memory at {:#x}",
+            total_size,
+            base_address
+        );
+    }
+    let permanent_storage = block as *mut u8;
+    let transient_storage = unsafe { permanent_storage.add(permanent_storage_size) };
+    Ok(GameMemory {
+        version: GAME_MEMORY_VERSION,
+        size: std::mem::size_of::<GameMemory>() as u32,
+        permanent_storage_size,
+        permanent_storage,
+        transient_storage_size,
+        transient_storage,
+        permanent_arena: MemoryArena::new(b"permanent\0", permanent_storage, permanent_storage_size),
+        transient_arena: MemoryArena::new(b"transient\0", transient_storage, transient_storage_size),
+        arena_api: ArenaApi {
+            push: arena_push,
+            begin_temporary: arena_begin_temporary,
+            end_temporary: arena_end_temporary,
+        },
+        profiler_api: ProfilerApi::new(),
+        overlay_api: OverlayApi::new(),
+        log_api: LogApi::new(),
+        console_api: ConsoleApi::new(),
+        tweak_api: TweakApi::new(),
+        layer_api: LayerApi::new(),
+    })
+}
+
+// Far away from anything the loader or the heap would hand out
+#[cfg(target_pointer_width = "64")]
+fn fixed_base_address() -> usize {
+    terabytes(2)
+}
+
+#[cfg(not(target_pointer_width = "64"))]
+fn fixed_base_address() -> usize {
+    0
+}
+
+impl MemoryArena {
+    fn new(name: &'static [u8], base: *mut u8, size: usize) -> Self {
+        Self {
+            name: name.as_ptr().cast(),
+            base,
+            size,
+            used: 0,
+            high_water_mark: 0,
+            temporary_count: 0,
+            tags: ArenaTags::default(),
+        }
+    }
+
+    fn name(&self) -> String {
+        unsafe { CStr::from_ptr(self.name) }.to_string_lossy().into_owned()
+    }
+
+    fn usage(&self) -> ArenaUsage {
+        let mut tags = BTreeMap::new();
+        for tag in &self.tags.tags[..self.tags.count as usize] {
+            *tags.entry(tag.name()).or_insert(0) += tag.bytes;
+        }
+        ArenaUsage {
+            name: self.name(),
+            size: self.size,
+            used: self.used,
+            high_water_mark: self.high_water_mark,
+            tags,
+        }
+    }
+}
+
+impl ArenaTag {
+    fn new(name: &[u8], bytes: usize) -> Self {
+        let mut tag = Self {
+            name: [0; ARENA_TAG_NAME_SIZE],
+            bytes,
+        };
+        let len = name.len().min(ARENA_TAG_NAME_SIZE);
+        tag.name[..len].copy_from_slice(&name[..len]);
+        tag
+    }
+
+    fn name(&self) -> String {
+        let len = self
+            .name
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(ARENA_TAG_NAME_SIZE);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+}
+
+impl ArenaTags {
+    fn add(&mut self, name: &[u8], bytes: usize) {
+        let key = ArenaTag::new(name, 0).name;
+        let count = self.count as usize;
+        if let Some(tag) = self.tags[..count].iter_mut().find(|tag| tag.name == key) {
+            tag.bytes += bytes;
+        } else if count < ARENA_TAG_COUNT {
+            self.tags[count] = ArenaTag::new(name, bytes);
+            self.count += 1;
+        } else {
+            let other = &mut self.tags[ARENA_TAG_COUNT - 1];
+            *other = ArenaTag::new(b"other", other.bytes + bytes);
+        }
+    }
+}
+
+unsafe extern "C" fn arena_push(
+    arena: *mut MemoryArena,
+    size: usize,
+    align: usize,
+    tag: *const c_char,
+) -> *mut u8 {
+    let arena = &mut *arena;
+    let tag = if tag.is_null() {
+        b"untagged".as_slice()
+    } else {
+        CStr::from_ptr(tag).to_bytes()
+    };
+    let align = align.max(1);
+    if !align.is_power_of_two() {
+        log_error!(
+            "memory",
+            "{} arena: alignment {} for '{}' is not a power of two",
+            arena.name(),
+            align,
+            String::from_utf8_lossy(tag)
+        );
+        std::process::abort();
+    }
+    let start = arena.base as usize + arena.used;
+    let padding = start.wrapping_neg() & (align - 1);
+    let new_used = arena
+        .used
+        .checked_add(padding)
+        .and_then(|used| used.checked_add(size))
+        .filter(|used| *used <= arena.size);
+    let Some(new_used) = new_used else {
+        log_error!(
+            "memory",
+            "{} arena overflowed: pushing {} bytes for '{}' with {} of {} bytes already used",
+            arena.name(),
+            size,
+            String::from_utf8_lossy(tag),
+            arena.used,
+            arena.size
+        );
+        std::process::abort();
+    };
+    let offset = arena.used;
+    arena.used = new_used;
+    arena.high_water_mark = arena.high_water_mark.max(new_used);
+    arena.tags.add(tag, padding + size);
+    arena.base.add(offset + padding)
+}
+
+unsafe extern "C" fn arena_begin_temporary(arena: *mut MemoryArena) -> TemporaryMemory {
+    (*arena).temporary_count += 1;
+    TemporaryMemory {
+        arena,
+        used: (*arena).used,
+        tags: (*arena).tags,
+    }
+}
+
+unsafe extern "C" fn arena_end_temporary(temporary_memory: TemporaryMemory) {
+    let arena = &mut *temporary_memory.arena;
+    if arena.temporary_count == 0 || temporary_memory.used > arena.used {
+        log_error!("memory", "{} arena: ended a temporary memory scope that isn't open", arena.name());
+        std::process::abort();
+    }
+    arena.temporary_count -= 1;
+    arena.used = temporary_memory.used;
+    arena.tags = temporary_memory.tags;
+}
+
+// Call once per frame before the game updates. Everything pushed onto the
+// transient arena last frame is gone after this.
+pub fn begin_frame(memory: &mut GameMemory) {
+    let transient_arena = &mut memory.transient_arena;
+    if transient_arena.temporary_count != 0 {
+        log_error!(
+            "memory",
+            "{} arena: {} temporary memory scopes were left open last frame",
+            transient_arena.name(),
+            transient_arena.temporary_count
+        );
+        std::process::abort();
+    }
+    transient_arena.used = 0;
+    transient_arena.tags = ArenaTags::default();
+}
+
+// Call once per frame after the game updates, so the transient arena shows
+// what this frame used.
+pub fn publish_stats(memory: &GameMemory) {
+    let usage = vec![memory.permanent_arena.usage(), memory.transient_arena.usage()];
+    edit_global!(memory_stats, MEMORY_STATS, {
+        *memory_stats = usage;
+    });
+}
+
+// A copy of the permanent arena, taken and put back with a plain memcpy, so
+// even snapshots of hundreds of MB take milliseconds. Only the arena up to its
+// high water mark is copied, past that nothing was ever pushed and the memory
+// is still zero. Optionally the copy lives in a memory mapped file, which then
+// survives restarts and costs no extra time to write, the OS flushes it
+// behind our back.
+pub struct MemorySnapshot {
+    storage: SnapshotStorage,
+    used: usize,
+    // Also how many bytes the snapshot holds
+    high_water_mark: usize,
+    tags: ArenaTags,
+}
+
+enum SnapshotStorage {
+    Heap(Vec<u8>),
+    Mapped(MappedFile),
+}
+
+// Mapped snapshot files start with this, followed by the size of the arena,
+// used and high water mark as little endian u64s, then the arena up to the
+// high water mark
+const SNAPSHOT_MAGIC: [u8; 8] = *b"CMMEMSNP";
+const SNAPSHOT_HEADER_SIZE: usize = 32;
+
+struct MappedFile {
+    // Kept open for as long as the view is mapped
+    _file: std::fs::File,
+    mapping: HANDLE,
+    view: *mut u8,
+}
+
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        unsafe {
+            UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS {
+                Value: self.view.cast(),
+            });
+            CloseHandle(self.mapping);
+        }
+    }
+}
+
+impl std::fmt::Debug for MemorySnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let storage = match self.storage {
+            SnapshotStorage::Heap(_) => "heap",
+            SnapshotStorage::Mapped(_) => "mapped file",
+        };
+        f.debug_struct("MemorySnapshot")
+            .field("storage", &storage)
+            .field("used", &self.used)
+            .finish()
+    }
+}
+
+impl MappedFile {
+    fn map(file: std::fs::File, len: usize) -> anyhow::Result<Self> {
+        unsafe {
+            let mapping = CreateFileMappingA(
+                file.as_raw_handle() as HANDLE,
+                std::ptr::null(),
+                PAGE_READWRITE,
+                (len as u64 >> 32) as u32,
+                len as u32,
+                std::ptr::null(),
+            );
+            if mapping == 0 {
+                bail!("couldn't create a file mapping of {} bytes", len);
+            }
+            let view = MapViewOfFile(mapping, FILE_MAP_ALL_ACCESS, 0, 0, len);
+            if view.Value.is_null() {
+                CloseHandle(mapping);
+                bail!("couldn't map a view of {} bytes", len);
+            }
+            Ok(Self {
+                _file: file,
+                mapping,
+                view: view.Value.cast(),
+            })
+        }
+    }
+}
+
+impl MemorySnapshot {
+    // Copies the permanent arena, into the file at path when there is one.
+    // The file is created or overwritten.
+    pub fn capture(memory: &GameMemory, path: Option<&Path>) -> anyhow::Result<Self> {
+        let arena = &memory.permanent_arena;
+        let bytes = permanent_bytes(memory);
+        let storage = match path {
+            Some(path) => {
+                let len = SNAPSHOT_HEADER_SIZE + bytes.len();
+                let file = std::fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(path)
+                    .with_context(|| format!("couldn't create {}", path.display()))?;
+                file.set_len(len as u64)?;
+                let mapped_file = MappedFile::map(file, len)?;
+                unsafe {
+                    let header = [
+                        u64::from_le_bytes(SNAPSHOT_MAGIC),
+                        arena.size as u64,
+                        arena.used as u64,
+                        arena.high_water_mark as u64,
+                    ];
+                    for (i, value) in header.iter().enumerate() {
+                        std::ptr::copy_nonoverlapping(
+                            value.to_le_bytes().as_ptr(),
+                            mapped_file.view.add(i * 8),
+                            8,
+                        );
+                    }
+                    std::ptr::copy_nonoverlapping(
+                        bytes.as_ptr(),
+                        mapped_file.view.add(SNAPSHOT_HEADER_SIZE),
+                        bytes.len(),
+                    );
+                }
+                SnapshotStorage::Mapped(mapped_file)
+            }
+            None => SnapshotStorage::Heap(bytes.to_vec()),
+        };
+        Ok(Self {
+            storage,
+            used: arena.used,
+            high_water_mark: arena.high_water_mark,
+            tags: arena.tags,
+        })
+    }
+
+    // Maps a snapshot file written by capture in an earlier run
+    pub fn open(memory: &GameMemory, path: &Path) -> anyhow::Result<Self> {
+        let arena = &memory.permanent_arena;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("couldn't open {}", path.display()))?;
+        let len = file.metadata()?.len() as usize;
+        if len < SNAPSHOT_HEADER_SIZE || len > SNAPSHOT_HEADER_SIZE + arena.size {
+            bail!(
+                "{} holds {} bytes, the permanent arena is {} bytes",
+                path.display(),
+                len.saturating_sub(SNAPSHOT_HEADER_SIZE),
+                arena.size
+            );
+        }
+        let mapped_file = MappedFile::map(file, len)?;
+        let mut header = [0u64; 4];
+        for (i, value) in header.iter_mut().enumerate() {
+            let mut bytes = [0u8; 8];
+            unsafe { std::ptr::copy_nonoverlapping(mapped_file.view.add(i * 8), bytes.as_mut_ptr(), 8) };
+            *value = u64::from_le_bytes(bytes);
+        }
+        if header[0] != u64::from_le_bytes(SNAPSHOT_MAGIC)
+            || header[1] as usize != arena.size
+            || header[3] as usize != len - SNAPSHOT_HEADER_SIZE
+            || header[2] > header[3]
+        {
+            bail!("{} is not a memory snapshot of this arena", path.display());
+        }
+        let used = header[2] as usize;
+        Ok(Self {
+            storage: SnapshotStorage::Mapped(mapped_file),
+            used,
+            high_water_mark: header[3] as usize,
+            // Which tag each block had isn't kept on disk
+            tags: {
+                let mut tags = ArenaTags::default();
+                tags.add(b"snapshot", used);
+                tags
+            },
+        })
+    }
+
+    pub fn restore(&self, memory: &mut GameMemory) {
+        let bytes = match &self.storage {
+            SnapshotStorage::Heap(bytes) => bytes.as_slice(),
+            SnapshotStorage::Mapped(mapped_file) => unsafe {
+                std::slice::from_raw_parts(mapped_file.view.add(SNAPSHOT_HEADER_SIZE), self.high_water_mark)
+            },
+        };
+        restore_permanent(memory, bytes, self.used, &self.tags);
+    }
+}
+
+// The permanent arena up to its high water mark, the rest is still zero
+pub fn permanent_bytes(memory: &GameMemory) -> &[u8] {
+    let arena = &memory.permanent_arena;
+    unsafe { std::slice::from_raw_parts(arena.base, arena.high_water_mark) }
+}
+
+// Puts back what permanent_bytes returned, with the arena's used and tags as
+// they were then. Zeroes whatever was pushed past it since.
+pub fn restore_permanent(memory: &mut GameMemory, bytes: &[u8], used: usize, tags: &ArenaTags) {
+    let arena = &mut memory.permanent_arena;
+    let len = bytes.len().min(arena.size);
+    unsafe {
+        std::ptr::copy_nonoverlapping(bytes.as_ptr(), arena.base, len);
+        if arena.high_water_mark > len {
+            std::ptr::write_bytes(arena.base.add(len), 0, arena.high_water_mark - len);
+        }
+    }
+    arena.used = used.min(len);
+    arena.high_water_mark = arena.high_water_mark.max(len);
+    arena.temporary_count = 0;
+    arena.tags = *tags;
+}
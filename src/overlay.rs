@@ -0,0 +1,226 @@
+// Debug overlay drawn over the game window.
+//
+// The host and the game put named lines into groups with set_line, the game
+// through the OverlayApi function table in GameMemory. A line keeps its text
+// until it is set again. F5 cycles between showing every group, each group on
+// its own and nothing.
+//
+// The host draws the overlay itself, right before presenting, with a built in
+// 5x8 pixel font, so it never touches GameState.texts.
+use fermium::{
+    blendmode::SDL_BLENDMODE_BLEND,
+    keycode::{SDL_Keycode, SDLK_F5},
+    prelude::{
+        SDL_GetRenderDrawBlendMode, SDL_GetRenderDrawColor, SDL_RenderFillRect, SDL_RenderFillRects,
+        SDL_SetRenderDrawBlendMode, SDL_SetRenderDrawColor,
+    },
+    rect::SDL_Rect,
+    renderer::SDL_Renderer,
+};
+use std::collections::BTreeMap;
+use std::ffi::{c_char, CStr};
+use std::sync::Mutex;
+
+pub const TOGGLE_KEY: SDL_Keycode = SDLK_F5;
+const SCALE: i32 = 2;
+const MARGIN: i32 = 4;
+const GLYPH_WIDTH: i32 = 5;
+const GLYPH_HEIGHT: i32 = 8;
+// Between glyphs and between lines, in font pixels
+const SPACING: i32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct OverlayApi {
+    // Group, name and text are C strings. A null text removes the line.
+    pub set_line: unsafe extern "C" fn(*const c_char, *const c_char, *const c_char),
+}
+
+impl OverlayApi {
+    pub fn new() -> Self {
+        Self {
+            set_line: overlay_set_line,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    All,
+    // Index into the groups, in name order
+    Only(usize),
+    Hidden,
+}
+
+struct Overlay {
+    // Group name to line name to text
+    groups: BTreeMap<String, BTreeMap<String, String>>,
+    mode: Mode,
+}
+
+lazy_static! {
+    static ref OVERLAY: Mutex<Overlay> = Mutex::new(Overlay {
+        groups: BTreeMap::new(),
+        mode: Mode::All,
+    });
+}
+
+pub fn set_line(group: &str, name: &str, text: impl Into<String>) {
+    OVERLAY
+        .lock()
+        .unwrap()
+        .groups
+        .entry(group.to_string())
+        .or_default()
+        .insert(name.to_string(), text.into());
+}
+
+pub fn remove_line(group: &str, name: &str) {
+    let mut overlay = OVERLAY.lock().unwrap();
+    if let Some(lines) = overlay.groups.get_mut(group) {
+        lines.remove(name);
+        if lines.is_empty() {
+            overlay.groups.remove(group);
+        }
+    }
+}
+
+// All groups, then each group alone, then nothing, then all groups again
+pub fn cycle_mode() {
+    let mut overlay = OVERLAY.lock().unwrap();
+    let group_count = overlay.groups.len();
+    overlay.mode = match overlay.mode {
+        Mode::All if group_count > 1 => Mode::Only(0),
+        Mode::Only(index) if index + 1 < group_count => Mode::Only(index + 1),
+        Mode::Hidden => Mode::All,
+        _ => Mode::Hidden,
+    };
+}
+
+// Call once per frame, right before presenting
+pub fn render(renderer: *mut SDL_Renderer) {
+    let mut lines = vec![];
+    {
+        let overlay = OVERLAY.lock().unwrap();
+        for (index, (group, group_lines)) in overlay.groups.iter().enumerate() {
+            let shown = match overlay.mode {
+                Mode::All => true,
+                Mode::Only(only) => only == index,
+                Mode::Hidden => false,
+            };
+            if !shown || group_lines.is_empty() {
+                continue;
+            }
+            lines.push(format!("[{}]", group));
+            for (name, text) in group_lines.iter() {
+                lines.push(format!("{}: {}", name, text));
+            }
+        }
+    }
+    if lines.is_empty() {
+        return;
+    }
+    let line_height = (GLYPH_HEIGHT + SPACING) * SCALE;
+    let glyph_advance = (GLYPH_WIDTH + SPACING) * SCALE;
+    let longest = lines.iter().map(|line| line.chars().count()).max().unwrap_or(0) as i32;
+    let background = SDL_Rect {
+        x: 0,
+        y: 0,
+        w: longest * glyph_advance + 2 * MARGIN,
+        h: lines.len() as i32 * line_height + 2 * MARGIN,
+    };
+    let mut pixels = vec![];
+    for (row, line) in lines.iter().enumerate() {
+        let y = MARGIN + row as i32 * line_height;
+        for (column, character) in line.chars().enumerate() {
+            let x = MARGIN + column as i32 * glyph_advance;
+            push_glyph(&mut pixels, character, x, y);
+        }
+    }
+    unsafe {
+        // The game's clear color has to survive us
+        let (mut r, mut g, mut b, mut a) = (0, 0, 0, 0);
+        let mut blend_mode = SDL_BLENDMODE_BLEND;
+        SDL_GetRenderDrawColor(renderer, &mut r, &mut g, &mut b, &mut a);
+        SDL_GetRenderDrawBlendMode(renderer, &mut blend_mode);
+        SDL_SetRenderDrawBlendMode(renderer, SDL_BLENDMODE_BLEND);
+        SDL_SetRenderDrawColor(renderer, 0, 0, 0, 160);
+        SDL_RenderFillRect(renderer, &background);
+        SDL_SetRenderDrawColor(renderer, 255, 255, 255, 255);
+        SDL_RenderFillRects(renderer, pixels.as_ptr(), pixels.len() as i32);
+        SDL_SetRenderDrawBlendMode(renderer, blend_mode);
+        SDL_SetRenderDrawColor(renderer, r, g, b, a);
+    }
+}
+
+fn push_glyph(pixels: &mut Vec<SDL_Rect>, character: char, x: i32, y: i32) {
+    let code = character as usize;
+    // Anything we have no glyph for is drawn as a question mark
+    let glyph = if (0x20..0x7f).contains(&code) {
+        &FONT[code - 0x20]
+    } else {
+        &FONT['?' as usize - 0x20]
+    };
+    for (column, bits) in glyph.iter().enumerate() {
+        for row in 0..GLYPH_HEIGHT {
+            if bits & (1 << row) != 0 {
+                pixels.push(SDL_Rect {
+                    x: x + column as i32 * SCALE,
+                    y: y + row * SCALE,
+                    w: SCALE,
+                    h: SCALE,
+                });
+            }
+        }
+    }
+}
+
+unsafe extern "C" fn overlay_set_line(group: *const c_char, name: *const c_char, text: *const c_char) {
+    if group.is_null() || name.is_null() {
+        return;
+    }
+    let group = CStr::from_ptr(group).to_string_lossy();
+    let name = CStr::from_ptr(name).to_string_lossy();
+    if text.is_null() {
+        remove_line(&group, &name);
+    } else {
+        set_line(&group, &name, CStr::from_ptr(text).to_string_lossy());
+    }
+}
+
+// ASCII 0x20 to 0x7e, five columns per glyph with the top row in bit 0
+#[rustfmt::skip]
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5f, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7f, 0x14, 0x7f, 0x14], [0x24, 0x2a, 0x7f, 0x2a, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x56, 0x20, 0x50], [0x00, 0x08, 0x07, 0x03, 0x00], [0x00, 0x1c, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1c, 0x00], [0x2a, 0x1c, 0x7f, 0x1c, 0x2a], [0x08, 0x08, 0x3e, 0x08, 0x08],
+    [0x00, 0x80, 0x70, 0x30, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x00, 0x60, 0x60, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02], [0x3e, 0x51, 0x49, 0x45, 0x3e], [0x00, 0x42, 0x7f, 0x40, 0x00],
+    [0x72, 0x49, 0x49, 0x49, 0x46], [0x21, 0x41, 0x49, 0x4d, 0x33], [0x18, 0x14, 0x12, 0x7f, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39], [0x3c, 0x4a, 0x49, 0x49, 0x31], [0x41, 0x21, 0x11, 0x09, 0x07],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x46, 0x49, 0x49, 0x29, 0x1e], [0x00, 0x00, 0x14, 0x00, 0x00],
+    [0x00, 0x40, 0x34, 0x00, 0x00], [0x00, 0x08, 0x14, 0x22, 0x41], [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x59, 0x09, 0x06], [0x3e, 0x41, 0x5d, 0x59, 0x4e],
+    [0x7c, 0x12, 0x11, 0x12, 0x7c], [0x7f, 0x49, 0x49, 0x49, 0x36], [0x3e, 0x41, 0x41, 0x41, 0x22],
+    [0x7f, 0x41, 0x41, 0x41, 0x3e], [0x7f, 0x49, 0x49, 0x49, 0x41], [0x7f, 0x09, 0x09, 0x09, 0x01],
+    [0x3e, 0x41, 0x41, 0x51, 0x73], [0x7f, 0x08, 0x08, 0x08, 0x7f], [0x00, 0x41, 0x7f, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3f, 0x01], [0x7f, 0x08, 0x14, 0x22, 0x41], [0x7f, 0x40, 0x40, 0x40, 0x40],
+    [0x7f, 0x02, 0x1c, 0x02, 0x7f], [0x7f, 0x04, 0x08, 0x10, 0x7f], [0x3e, 0x41, 0x41, 0x41, 0x3e],
+    [0x7f, 0x09, 0x09, 0x09, 0x06], [0x3e, 0x41, 0x51, 0x21, 0x5e], [0x7f, 0x09, 0x19, 0x29, 0x46],
+    [0x26, 0x49, 0x49, 0x49, 0x32], [0x03, 0x01, 0x7f, 0x01, 0x03], [0x3f, 0x40, 0x40, 0x40, 0x3f],
+    [0x1f, 0x20, 0x40, 0x20, 0x1f], [0x3f, 0x40, 0x38, 0x40, 0x3f], [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03], [0x61, 0x59, 0x49, 0x4d, 0x43], [0x00, 0x7f, 0x41, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x00, 0x41, 0x41, 0x41, 0x7f], [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40], [0x00, 0x03, 0x07, 0x08, 0x00], [0x20, 0x54, 0x54, 0x78, 0x40],
+    [0x7f, 0x28, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x28], [0x38, 0x44, 0x44, 0x28, 0x7f],
+    [0x38, 0x54, 0x54, 0x54, 0x18], [0x00, 0x08, 0x7e, 0x09, 0x02], [0x18, 0xa4, 0xa4, 0x9c, 0x78],
+    [0x7f, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7d, 0x40, 0x00], [0x20, 0x40, 0x40, 0x3d, 0x00],
+    [0x7f, 0x10, 0x28, 0x44, 0x00], [0x00, 0x41, 0x7f, 0x40, 0x00], [0x7c, 0x04, 0x78, 0x04, 0x78],
+    [0x7c, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38], [0xfc, 0x18, 0x24, 0x24, 0x18],
+    [0x18, 0x24, 0x24, 0x18, 0xfc], [0x7c, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x24],
+    [0x04, 0x04, 0x3f, 0x44, 0x24], [0x3c, 0x40, 0x40, 0x20, 0x7c], [0x1c, 0x20, 0x40, 0x20, 0x1c],
+    [0x3c, 0x40, 0x30, 0x40, 0x3c], [0x44, 0x28, 0x10, 0x28, 0x44], [0x4c, 0x90, 0x90, 0x90, 0x7c],
+    [0x44, 0x64, 0x54, 0x4c, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00], [0x00, 0x00, 0x77, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00], [0x02, 0x01, 0x02, 0x04, 0x02],
+];
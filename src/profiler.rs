@@ -0,0 +1,195 @@
+// Scoped timers for the host loop and the game.
+//
+// timed_block!("name") times the rest of the enclosing scope. Blocks opened
+// inside another block nest under it, and every time the same block is hit
+// in a frame it adds to the same entry, so a frame boils down to a small tree
+// of hit counts, cycles and milliseconds. end_frame closes the frame and
+// pushes it onto PROFILE_FRAMES, the last PROFILE_FRAME_COUNT frames of which
+// the editor's profiler panel shows.
+//
+// The game gets the same thing through the ProfilerApi function table in
+// GameMemory: begin_block with a name, end_block when the scope ends.
+//
+// While a trace is running every block is also written to it, see trace.rs.
+use engine::edit_global;
+use std::ffi::{c_char, CStr};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::{cycles, trace, PROFILE_FRAMES};
+
+pub const PROFILE_FRAME_COUNT: usize = 120;
+
+macro_rules! timed_block {
+    ($name:expr) => {
+        let _timed_block = $crate::profiler::TimedBlock::new($name);
+    };
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProfilerApi {
+    // The name is a C string, it may be null
+    pub begin_block: unsafe extern "C" fn(*const c_char),
+    // Ends the block begun last
+    pub end_block: unsafe extern "C" fn(),
+}
+
+impl ProfilerApi {
+    pub fn new() -> Self {
+        Self {
+            begin_block: profiler_begin_block,
+            end_block: profiler_end_block,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProfileBlock {
+    pub name: String,
+    // Index of the enclosing block in FrameProfile.blocks, which always comes
+    // before this one
+    pub parent: Option<usize>,
+    pub hit_count: u32,
+    pub cycles: u64,
+    pub milliseconds: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FrameProfile {
+    pub frame: usize,
+    pub cycles: u64,
+    pub milliseconds: f32,
+    pub blocks: Vec<ProfileBlock>,
+}
+
+struct OpenBlock {
+    index: usize,
+    start: cycles::Reading,
+    start_time: Instant,
+}
+
+struct Profiler {
+    frame: FrameProfile,
+    open_blocks: Vec<OpenBlock>,
+    // Counts from the start of the frame, and checks every block's cycles for
+    // core migrations too
+    cycle_counter: cycles::CycleCounter,
+    frame_start_time: Instant,
+}
+
+lazy_static! {
+    static ref PROFILER: Mutex<Profiler> = Mutex::new(Profiler {
+        frame: FrameProfile::default(),
+        open_blocks: vec![],
+        cycle_counter: cycles::CycleCounter::new(),
+        frame_start_time: Instant::now(),
+    });
+}
+
+// Ends the block when dropped
+pub struct TimedBlock;
+
+impl TimedBlock {
+    pub fn new(name: &'static str) -> Self {
+        begin_block(name);
+        TimedBlock
+    }
+}
+
+impl Drop for TimedBlock {
+    fn drop(&mut self) {
+        end_block();
+    }
+}
+
+pub fn begin_block(name: &str) {
+    let start = cycles::read();
+    let mut profiler = PROFILER.lock().unwrap();
+    let parent = profiler.open_blocks.last().map(|open_block| open_block.index);
+    let existing = profiler
+        .frame
+        .blocks
+        .iter()
+        .position(|block| block.parent == parent && block.name == name);
+    let index = match existing {
+        Some(index) => index,
+        None => {
+            profiler.frame.blocks.push(ProfileBlock {
+                name: name.to_string(),
+                parent,
+                ..Default::default()
+            });
+            profiler.frame.blocks.len() - 1
+        }
+    };
+    profiler.open_blocks.push(OpenBlock {
+        index,
+        start,
+        start_time: Instant::now(),
+    });
+}
+
+pub fn end_block() {
+    let end = cycles::read();
+    let end_time = Instant::now();
+    let mut profiler = PROFILER.lock().unwrap();
+    let Some(open_block) = profiler.open_blocks.pop() else {
+        log_warn!("profiler", "Ended a block that was never begun");
+        return;
+    };
+    let cycles = profiler
+        .cycle_counter
+        .ticks_between(open_block.start, open_block.start_time, end, end_time);
+    let block = &mut profiler.frame.blocks[open_block.index];
+    block.hit_count += 1;
+    block.cycles += cycles;
+    block.milliseconds += 1000.0 * end_time.duration_since(open_block.start_time).as_secs_f32();
+    if trace::is_tracing() {
+        trace::complete_event(&block.name, open_block.start_time, end_time);
+    }
+}
+
+// Call once per frame, outside of any block. Blocks the game left open are
+// dropped from the frame. Returns the finished frame.
+pub fn end_frame(frame: usize) -> FrameProfile {
+    let end_time = Instant::now();
+    let frame_profile = {
+        let mut profiler = PROFILER.lock().unwrap();
+        if !profiler.open_blocks.is_empty() {
+            log_warn!(
+                "profiler",
+                "{} blocks were still open at the end of frame {}",
+                profiler.open_blocks.len(),
+                frame
+            );
+            profiler.open_blocks.clear();
+        }
+        let mut frame_profile = std::mem::take(&mut profiler.frame);
+        frame_profile.frame = frame;
+        frame_profile.cycles = profiler.cycle_counter.elapsed();
+        frame_profile.milliseconds =
+            1000.0 * end_time.duration_since(profiler.frame_start_time).as_secs_f32();
+        profiler.frame_start_time = end_time;
+        frame_profile
+    };
+    edit_global!(profile_frames, PROFILE_FRAMES, {
+        if profile_frames.len() >= PROFILE_FRAME_COUNT {
+            profile_frames.pop_front();
+        }
+        profile_frames.push_back(frame_profile.clone());
+    });
+    frame_profile
+}
+
+unsafe extern "C" fn profiler_begin_block(name: *const c_char) {
+    if name.is_null() {
+        begin_block("unnamed");
+    } else {
+        begin_block(&CStr::from_ptr(name).to_string_lossy());
+    }
+}
+
+unsafe extern "C" fn profiler_end_block() {
+    end_block();
+}
@@ -0,0 +1,333 @@
+// Handmade style looped input recording.
+//
+// F9 snapshots GAME_STATE and starts capturing input. F10 stops the capture
+// and loops playback of it (restoring the snapshot each time round) until F10
+// is pressed again. A paused or rewound game can't be recorded, unpause or
+// resume it first.
+//
+// Input is recorded per simulation frame together with the timing info the
+// game was fed that frame, and played back the same way, so a loop is bit
+// exact no matter what frame rate it is played back at.
+//
+// With --record the session is recorded from startup and every stopped
+// recording is also written to that file, see replay.rs.
+//
+// Every --checkpoint-interval frames the recording also stores the state the
+// game ended the frame with, and playback verifies against it, see desync.rs.
+//
+// Starting a recording also snapshots the permanent arena of the game memory
+// and the tweak values, which playback puts back with the state. With --record the snapshot
+// goes to <record file>.mem, which --replay picks up when it is there.
+use anyhow::bail;
+use engine::{access_global, edit_global, GameInput};
+use fermium::keycode::{SDL_Keycode, SDLK_F10, SDLK_F9};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::{
+    desync,
+    memory::{GameMemory, MemorySnapshot},
+    replay, trace, tweaks, Platform, RecordedFrame, RecordedGame, GAME_INPUT, GAME_STATE,
+    RECORDED_CHECKPOINTS, RECORDED_GAME_STATE, RECORDED_INPUT, RECORDING,
+};
+
+pub const START_RECORDING_KEY: SDL_Keycode = SDLK_F9;
+pub const PLAYBACK_KEY: SDL_Keycode = SDLK_F10;
+
+#[derive(Debug, Clone)]
+pub struct Playback {
+    recorded_game: RecordedGame,
+    memory_snapshot: Option<Arc<MemorySnapshot>>,
+    frame: usize,
+    first_desync: Option<usize>,
+    desynced_this_loop: bool,
+    // Whether update fed the game a frame this time round, a paused replay
+    // sits on the same frame and has nothing new to verify
+    updated: bool,
+}
+
+impl Playback {
+    fn new(
+        recorded_game: RecordedGame,
+        memory_snapshot: Option<Arc<MemorySnapshot>>,
+        memory: &mut GameMemory,
+    ) -> Self {
+        restore_game_state(&recorded_game, memory_snapshot.as_deref(), memory);
+        Self {
+            recorded_game,
+            memory_snapshot,
+            frame: 0,
+            first_desync: None,
+            desynced_this_loop: false,
+            updated: false,
+        }
+    }
+    // The first frame that didn't match the recording, on any pass so far
+    pub fn first_desync(&self) -> Option<usize> {
+        self.first_desync
+    }
+    pub fn finished_pass(&self) -> bool {
+        self.frame >= self.recorded_game.recorded_input.len()
+    }
+    // Feeds the next recorded frame to the game, starting the loop over once
+    // we run past the end of the recording. Call once per frame, after the
+    // platform events are handled and before the game updates.
+    pub fn update(&mut self, memory: &mut GameMemory) {
+        if self.recorded_game.recorded_input.is_empty() {
+            return;
+        }
+        if self.finished_pass() {
+            trace::marker("playback loop");
+            restore_game_state(&self.recorded_game, self.memory_snapshot.as_deref(), memory);
+            self.frame = 0;
+            self.desynced_this_loop = false;
+        }
+        let recorded_frame = &self.recorded_game.recorded_input[self.frame];
+        edit_global!(game_input, GAME_INPUT, {
+            game_input.extend(recorded_frame.inputs.iter().cloned());
+        });
+        edit_global!(game_state, GAME_STATE, {
+            game_state.timing_info = recorded_frame.timing_info.clone();
+        });
+        self.frame += 1;
+        self.updated = true;
+    }
+    // Checks the state the game just ended the frame with against the
+    // recording. Only the first divergent frame of each pass is reported,
+    // everything after it is going to differ anyway.
+    fn verify(&mut self, dump_prefix: &Path) {
+        if !std::mem::take(&mut self.updated) || self.desynced_this_loop {
+            return;
+        }
+        let Some(frame) = self.frame.checked_sub(1) else {
+            return;
+        };
+        let checkpoints = &self.recorded_game.checkpoints;
+        let Ok(index) = checkpoints.binary_search_by_key(&frame, |checkpoint| checkpoint.frame)
+        else {
+            return;
+        };
+        let checkpoint = &checkpoints[index];
+        access_global!(game_state, GAME_STATE, {
+            if desync::hash_game_state(&game_state) != checkpoint.hash {
+                desync::report(checkpoint, &game_state, dump_prefix);
+                self.desynced_this_loop = true;
+                self.first_desync.get_or_insert(frame);
+            }
+        });
+    }
+}
+
+pub fn is_hotkey(key: SDL_Keycode) -> bool {
+    key == START_RECORDING_KEY || key == PLAYBACK_KEY
+}
+
+pub fn handle_hotkey(platform: &mut Platform, key: SDL_Keycode) {
+    if key == START_RECORDING_KEY {
+        if let Err(error) = record(platform) {
+            log_warn!("recording", "Not recording: {}", error);
+        }
+    } else if key == PLAYBACK_KEY {
+        stop_or_play(platform);
+    }
+}
+
+// Stops any loop and starts recording, unless we already are
+pub fn record(platform: &mut Platform) -> anyhow::Result<()> {
+    if is_recording() {
+        return Ok(());
+    }
+    // A paused game gets no input and no time, which the recording would
+    // have it get on playback
+    if platform.paused {
+        bail!("can't record while paused");
+    }
+    // Rewind would put the frame it's on back over every recorded one
+    if platform.rewind.is_rewound() {
+        bail!("can't record while rewound, resume first");
+    }
+    platform.playback = None;
+    start_recording(platform);
+    Ok(())
+}
+
+// Stops the recording and loops it, or stops the loop
+pub fn stop_or_play(platform: &mut Platform) {
+    if is_recording() {
+        let recorded_game = stop_recording(platform);
+        let memory_snapshot = platform.recording_memory.take();
+        start_playback(platform, recorded_game, memory_snapshot);
+    } else {
+        platform.playback = None;
+    }
+}
+
+pub fn is_recording() -> bool {
+    *RECORDING.lock().unwrap()
+}
+
+// Adds the input to the frame currently being recorded. Does nothing when we
+// are not recording.
+pub fn record_input(input: &GameInput) {
+    if !is_recording() {
+        return;
+    }
+    edit_global!(recorded_input, RECORDED_INPUT, {
+        if let Some(recorded_frame) = recorded_input.last_mut() {
+            recorded_frame.inputs.push(input.clone());
+        }
+    });
+}
+
+// Closes the frame currently being recorded with the timing info the game is
+// about to see, and opens the next one. Call once per frame, right before the
+// game updates.
+pub fn end_input_frame() {
+    if !is_recording() {
+        return;
+    }
+    let mut timing_info = Default::default();
+    access_global!(game_state, GAME_STATE, {
+        timing_info = game_state.timing_info.clone();
+    });
+    edit_global!(recorded_input, RECORDED_INPUT, {
+        if let Some(recorded_frame) = recorded_input.last_mut() {
+            recorded_frame.timing_info = timing_info;
+        }
+        recorded_input.push(RecordedFrame::default());
+    });
+}
+
+// Call once per frame, right after the game updates. Checkpoints the state
+// while recording and verifies it during playback.
+pub fn after_game_update(platform: &mut Platform) {
+    if is_recording() {
+        let mut frame = 0;
+        access_global!(recorded_input, RECORDED_INPUT, {
+            // end_input_frame already opened the next frame
+            frame = recorded_input.len().saturating_sub(2);
+        });
+        if frame % platform.cli.checkpoint_interval.max(1) == 0 {
+            let mut checkpoint = None;
+            access_global!(game_state, GAME_STATE, {
+                checkpoint = Some(desync::checkpoint(frame, &game_state));
+            });
+            edit_global!(recorded_checkpoints, RECORDED_CHECKPOINTS, {
+                recorded_checkpoints.extend(checkpoint);
+            });
+        }
+    }
+    if let Some(playback) = platform.playback.as_mut() {
+        let dump_prefix = platform
+            .cli
+            .replay
+            .clone()
+            .unwrap_or_else(|| "recording".into());
+        playback.verify(&dump_prefix);
+        if platform.cli.verify && playback.finished_pass() {
+            match playback.first_desync() {
+                Some(frame) => log_error!("replay", "Replay desynced at frame {}", frame),
+                None => log_info!("replay", "Replay verified, no desync"),
+            }
+            platform.running = false;
+        }
+    }
+}
+
+pub fn start_playback(
+    platform: &mut Platform,
+    recorded_game: RecordedGame,
+    memory_snapshot: Option<Arc<MemorySnapshot>>,
+) {
+    trace::marker("playback start");
+    platform.playback = Some(Playback::new(recorded_game, memory_snapshot, &mut platform.memory));
+}
+
+pub fn start_recording(platform: &mut Platform) {
+    trace::marker("recording start");
+    // Unmap the previous snapshot first, it may be the file we write to
+    platform.recording_memory = None;
+    let snapshot_path = platform.cli.record.as_deref().map(replay::memory_snapshot_path);
+    match MemorySnapshot::capture(&platform.memory, snapshot_path.as_deref()) {
+        Ok(memory_snapshot) => platform.recording_memory = Some(Arc::new(memory_snapshot)),
+        Err(error) => log_warn!("recording", "Failed to snapshot game memory, recording without it: {:?}", error),
+    }
+    platform.recording_tweaks = tweaks::values();
+    edit_global!(game_state, GAME_STATE, {
+        edit_global!(recorded_game_state, RECORDED_GAME_STATE, {
+            *recorded_game_state = game_state.clone();
+        });
+        game_state.recording = true;
+    });
+    // Input that arrived earlier this frame hasn't reached the game yet, so
+    // it is the start of the first recorded frame
+    let mut pending_input = vec![];
+    access_global!(game_input, GAME_INPUT, {
+        pending_input = game_input.clone();
+    });
+    edit_global!(recorded_checkpoints, RECORDED_CHECKPOINTS, {
+        recorded_checkpoints.clear();
+    });
+    edit_global!(recorded_input, RECORDED_INPUT, {
+        recorded_input.clear();
+        recorded_input.push(RecordedFrame {
+            inputs: pending_input,
+            ..Default::default()
+        });
+    });
+    edit_global!(recording, RECORDING, {
+        *recording = true;
+    });
+}
+
+// The memory snapshot taken at the start stays in platform.recording_memory
+pub fn stop_recording(platform: &Platform) -> RecordedGame {
+    trace::marker("recording stop");
+    edit_global!(recording, RECORDING, {
+        *recording = false;
+    });
+    edit_global!(game_state, GAME_STATE, {
+        game_state.recording = false;
+    });
+    let mut recorded_game = RecordedGame {
+        tweaks: platform.recording_tweaks.clone(),
+        ..Default::default()
+    };
+    access_global!(recorded_game_state, RECORDED_GAME_STATE, {
+        recorded_game.game_state_at_start = recorded_game_state.clone();
+    });
+    edit_global!(recorded_input, RECORDED_INPUT, {
+        // The frame we are in the middle of never reached the game
+        recorded_input.pop();
+        recorded_game.recorded_input = recorded_input.clone();
+    });
+    access_global!(recorded_checkpoints, RECORDED_CHECKPOINTS, {
+        recorded_game.checkpoints = recorded_checkpoints.clone();
+    });
+    if let Some(path) = platform.cli.record.as_ref() {
+        match replay::save(path, platform, &recorded_game) {
+            Ok(()) => log_info!("recording", "Saved recording to {}", path.display()),
+            Err(error) => log_error!("recording", "Failed to save recording to {}: {:?}", path.display(), error),
+        }
+    }
+    recorded_game
+}
+
+fn restore_game_state(
+    recorded_game: &RecordedGame,
+    memory_snapshot: Option<&MemorySnapshot>,
+    memory: &mut GameMemory,
+) {
+    if let Some(memory_snapshot) = memory_snapshot {
+        memory_snapshot.restore(memory);
+    }
+    tweaks::apply(&recorded_game.tweaks);
+    edit_global!(game_state, GAME_STATE, {
+        *game_state = recorded_game.game_state_at_start.clone();
+        game_state.recording = false;
+    });
+    // Anything the game hasn't consumed yet belongs to the previous loop
+    edit_global!(game_input, GAME_INPUT, {
+        game_input.clear();
+    });
+}
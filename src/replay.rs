@@ -0,0 +1,153 @@
+// Replay files, a RecordedGame written to disk with --record and played back
+// with --replay.
+//
+// Layout: magic, format version (u32 little endian), then the bincode encoded
+// ReplayHeader followed by the bincode encoded RecordedGame. The format
+// version comes before anything bincode encoded, because bincode isn't self
+// describing and an old file would otherwise decode into garbage.
+use anyhow::{bail, Context};
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{Platform, RecordedGame};
+
+const REPLAY_MAGIC: [u8; 4] = *b"CMRP";
+// Bump this whenever ReplayHeader or RecordedGame change shape, or what goes
+// into the checkpoint hashes changes
+const REPLAY_FORMAT_VERSION: u32 = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplayHeader {
+    host_version: String,
+    // Hash of the game library that was loaded when the replay was recorded
+    game_library_hash: u64,
+    window_width: i32,
+    window_height: i32,
+    cli_options: Vec<String>,
+}
+
+impl ReplayHeader {
+    pub fn new(platform: &Platform) -> Self {
+        Self {
+            host_version: env!("CARGO_PKG_VERSION").to_string(),
+            game_library_hash: platform.game_library_hash,
+            window_width: platform.cli.width,
+            window_height: platform.cli.height,
+            cli_options: std::env::args().skip(1).collect(),
+        }
+    }
+}
+
+// 64 bit FNV-1a. Unlike DefaultHasher the result is stable across Rust
+// versions and machines, which matters for anything we write to disk.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn hash_file(path: &Path) -> anyhow::Result<u64> {
+    let bytes = std::fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    Ok(fnv1a(&bytes))
+}
+
+// bincode::deserialize_from with no limit trusts every length in the file, so
+// a corrupt one can ask for any amount of memory. Nothing in a file is longer
+// than the file, so its size makes a good limit. Encodes like
+// bincode::serialize does.
+pub fn deserialize_from<T: DeserializeOwned>(reader: impl Read, limit: u64) -> anyhow::Result<T> {
+    Ok(bincode::options()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize_from(reader)?)
+}
+
+// Where the game memory snapshot for the replay at path lives
+pub fn memory_snapshot_path(path: &Path) -> PathBuf {
+    let mut snapshot_path = path.as_os_str().to_owned();
+    snapshot_path.push(".mem");
+    PathBuf::from(snapshot_path)
+}
+
+pub fn save(path: &Path, platform: &Platform, recorded_game: &RecordedGame) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&REPLAY_MAGIC)?;
+    writer.write_all(&REPLAY_FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, &ReplayHeader::new(platform))?;
+    bincode::serialize_into(&mut writer, recorded_game)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Refuses replays from another format version or another build of the game,
+// those would desync immediately. A different host version or window size
+// only gets a warning.
+pub fn load(path: &Path, platform: &Platform) -> anyhow::Result<RecordedGame> {
+    let file = File::open(path).with_context(|| format!("couldn't open {}", path.display()))?;
+    let limit = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != REPLAY_MAGIC {
+        bail!("{} is not a replay file", path.display());
+    }
+    let mut format_version = [0u8; 4];
+    reader.read_exact(&mut format_version)?;
+    let format_version = u32::from_le_bytes(format_version);
+    if format_version != REPLAY_FORMAT_VERSION {
+        bail!(
+            "{} is replay format version {}, this build reads version {}",
+            path.display(),
+            format_version,
+            REPLAY_FORMAT_VERSION
+        );
+    }
+    let header: ReplayHeader = deserialize_from(&mut reader, limit)?;
+    let current = ReplayHeader::new(platform);
+    if header.game_library_hash != current.game_library_hash {
+        bail!(
+            "{} was recorded with a different build of the game library ({:016x}, loaded {:016x})",
+            path.display(),
+            header.game_library_hash,
+            current.game_library_hash
+        );
+    }
+    if header.host_version != current.host_version {
+        log_warn!(
+            "replay",
+            "{} was recorded with host version {}, this is {}",
+            path.display(),
+            header.host_version,
+            current.host_version
+        );
+    }
+    if (header.window_width, header.window_height) != (current.window_width, current.window_height) {
+        log_warn!(
+            "replay",
+            "{} was recorded at {}x{}, the window is {}x{}",
+            path.display(),
+            header.window_width,
+            header.window_height,
+            current.window_width,
+            current.window_height
+        );
+    }
+    // Only for the curious, playing back never has the same options as
+    // recording did
+    log_debug!(
+        "replay",
+        "{} was recorded with options: {}",
+        path.display(),
+        header.cli_options.join(" ")
+    );
+    let recorded_game: RecordedGame = deserialize_from(&mut reader, limit)?;
+    Ok(recorded_game)
+}
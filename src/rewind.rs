@@ -0,0 +1,447 @@
+// Rewind buffer for time travel debugging.
+//
+// Every frame the state the game ended up in, GAME_STATE and the permanent
+// arena of the game memory, is serialized into a ring buffer, along with the
+// input it was fed, so there is always the last few seconds to step back
+// through without having had a recording running. Full snapshots (keyframes)
+// are only stored every KEYFRAME_INTERVAL frames, the frames in between store
+// just the byte ranges that changed since the frame before. When the buffer
+// goes over --rewind-budget the oldest frames go. A budget of 0 turns rewind
+// off, which saves copying the state every frame.
+//
+// F6 steps back a frame, F7 steps forward again and F8 resumes the game from
+// the frame we are on, dropping everything after it. While rewound the game
+// is fed the rewound state every frame with no input and zero elapsed time,
+// so it keeps rendering without moving on.
+use engine::{access_global, edit_global, GameInput, GameState};
+use fermium::keycode::{SDL_Keycode, SDLK_F6, SDLK_F7, SDLK_F8};
+use std::collections::VecDeque;
+
+use crate::memory::{self, GameMemory};
+use crate::{recording, Platform, GAME_INPUT, GAME_STATE, REWIND_REQUESTS, REWIND_STATUS};
+
+pub const STEP_BACK_KEY: SDL_Keycode = SDLK_F6;
+pub const STEP_FORWARD_KEY: SDL_Keycode = SDLK_F7;
+pub const RESUME_KEY: SDL_Keycode = SDLK_F8;
+const KEYFRAME_INTERVAL: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RewindRequest {
+    StepBack,
+    StepForward,
+    // Index into the buffer, 0 is the oldest frame
+    Seek(usize),
+    Resume,
+}
+
+// What the editor needs to draw the scrub bar
+#[derive(Debug, Clone, Default)]
+pub struct RewindStatus {
+    pub frames: usize,
+    // Index of the frame we are rewound to, None while the game runs
+    pub cursor: Option<usize>,
+    pub bytes: usize,
+    pub budget: usize,
+    // What the game was fed to get to the frame we are rewound to
+    pub inputs: Vec<GameInput>,
+}
+
+#[derive(Debug, Clone)]
+enum EncodedState {
+    Keyframe(Vec<u8>),
+    // Runs of (unchanged length: u32, changed length: u32, changed bytes)
+    // against the frame before, see encode_delta
+    Delta(Vec<u8>),
+}
+
+impl EncodedState {
+    fn len(&self) -> usize {
+        match self {
+            EncodedState::Keyframe(bytes) | EncodedState::Delta(bytes) => bytes.len(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct RewindFrame {
+    state: EncodedState,
+    inputs: Vec<GameInput>,
+}
+
+impl RewindFrame {
+    fn size(&self) -> usize {
+        self.state.len() + self.inputs.len() * std::mem::size_of::<GameInput>()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RewindBuffer {
+    frames: VecDeque<RewindFrame>,
+    // The decoded newest frame, which the next delta is made against
+    newest_state: Vec<u8>,
+    frames_since_keyframe: usize,
+    // The input the game is about to be fed, for the frame it ends up in
+    pending_inputs: Vec<GameInput>,
+    cursor: Option<usize>,
+    bytes: usize,
+    budget: usize,
+    // Whether we said that a single frame doesn't fit the budget
+    warned_over_budget: bool,
+}
+
+impl RewindBuffer {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            newest_state: vec![],
+            frames_since_keyframe: 0,
+            pending_inputs: vec![],
+            cursor: None,
+            bytes: 0,
+            budget,
+            warned_over_budget: false,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.budget > 0
+    }
+
+    pub fn is_rewound(&self) -> bool {
+        self.cursor.is_some()
+    }
+
+    // Lets the game run on from whatever state something else put in place,
+    // like a save state, instead of the frame we are rewound to
+    pub fn stop_rewinding(&mut self) {
+        self.cursor = None;
+    }
+
+    fn push(&mut self, state: Vec<u8>, inputs: Vec<GameInput>) {
+        let encoded = if self.frames.is_empty()
+            || self.frames_since_keyframe + 1 >= KEYFRAME_INTERVAL
+            || state.len() != self.newest_state.len()
+        {
+            self.frames_since_keyframe = 0;
+            EncodedState::Keyframe(state.clone())
+        } else {
+            self.frames_since_keyframe += 1;
+            EncodedState::Delta(encode_delta(&self.newest_state, &state))
+        };
+        let frame = RewindFrame {
+            state: encoded,
+            inputs,
+        };
+        self.bytes += frame.size();
+        self.frames.push_back(frame);
+        self.newest_state = state;
+        while self.bytes > self.budget && self.frames.len() > 1 {
+            self.drop_oldest();
+        }
+        if self.bytes > self.budget && !std::mem::replace(&mut self.warned_over_budget, true) {
+            log_warn!(
+                "rewind",
+                "A single frame takes {} bytes, over the --rewind-budget of {}, so there is nothing to rewind to. \
+                 Raise the budget, or make it 0 to turn rewind off.",
+                self.bytes,
+                self.budget
+            );
+        }
+    }
+
+    // The frame after the oldest one becomes a keyframe if it isn't one
+    // already, since the frame its delta is against is going away.
+    fn drop_oldest(&mut self) {
+        let Some(oldest) = self.frames.pop_front() else {
+            return;
+        };
+        self.bytes -= oldest.size();
+        let EncodedState::Keyframe(oldest_state) = oldest.state else {
+            unreachable!("the oldest frame in the rewind buffer is always a keyframe");
+        };
+        if let Some(next) = self.frames.front_mut() {
+            if let EncodedState::Delta(delta) = &next.state {
+                let state = apply_delta(&oldest_state, delta);
+                self.bytes -= next.state.len();
+                self.bytes += state.len();
+                next.state = EncodedState::Keyframe(state);
+            }
+        }
+        if let Some(cursor) = self.cursor.as_mut() {
+            *cursor = cursor.saturating_sub(1);
+        }
+    }
+
+    fn decode(&self, index: usize) -> Vec<u8> {
+        let keyframe = (0..=index)
+            .rev()
+            .find(|i| matches!(self.frames[*i].state, EncodedState::Keyframe(_)))
+            .expect("the oldest frame in the rewind buffer is always a keyframe");
+        let EncodedState::Keyframe(bytes) = &self.frames[keyframe].state else {
+            unreachable!();
+        };
+        let mut state = bytes.clone();
+        for frame in self.frames.range(keyframe + 1..=index) {
+            if let EncodedState::Delta(delta) = &frame.state {
+                state = apply_delta(&state, delta);
+            }
+        }
+        state
+    }
+
+    fn seek(&mut self, index: usize) {
+        if self.frames.is_empty() {
+            return;
+        }
+        self.cursor = Some(index.min(self.frames.len() - 1));
+    }
+
+    fn step_back(&mut self) {
+        let newest = self.frames.len().saturating_sub(1);
+        self.seek(self.cursor.unwrap_or(newest).saturating_sub(1));
+    }
+
+    fn step_forward(&mut self) {
+        if let Some(cursor) = self.cursor {
+            self.seek(cursor + 1);
+        }
+    }
+
+    // Everything after the frame we are on is a future that won't happen now
+    fn resume(&mut self) {
+        let Some(cursor) = self.cursor.take() else {
+            return;
+        };
+        self.newest_state = self.decode(cursor);
+        while self.frames.len() > cursor + 1 {
+            if let Some(frame) = self.frames.pop_back() {
+                self.bytes -= frame.size();
+            }
+        }
+        // Walk back to the keyframe so the next keyframe comes on schedule
+        self.frames_since_keyframe = self
+            .frames
+            .iter()
+            .rev()
+            .take_while(|frame| matches!(frame.state, EncodedState::Delta(_)))
+            .count();
+    }
+
+    fn status(&self) -> RewindStatus {
+        RewindStatus {
+            frames: self.frames.len(),
+            cursor: self.cursor,
+            bytes: self.bytes,
+            budget: self.budget,
+            inputs: self
+                .cursor
+                .map(|cursor| self.frames[cursor].inputs.clone())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+// Stores the runs of bytes that differ between previous and current, which
+// must be the same length.
+fn encode_delta(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    let mut delta = vec![];
+    let mut position = 0;
+    while position < current.len() {
+        let unchanged = previous[position..]
+            .iter()
+            .zip(&current[position..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        position += unchanged;
+        let changed = previous[position..]
+            .iter()
+            .zip(&current[position..])
+            .take_while(|(a, b)| a != b)
+            .count();
+        delta.extend_from_slice(&(unchanged as u32).to_le_bytes());
+        delta.extend_from_slice(&(changed as u32).to_le_bytes());
+        delta.extend_from_slice(&current[position..position + changed]);
+        position += changed;
+    }
+    delta
+}
+
+fn apply_delta(previous: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut state = previous.to_vec();
+    let mut position = 0;
+    let mut read = 0;
+    while read + 8 <= delta.len() {
+        let unchanged = u32::from_le_bytes(delta[read..read + 4].try_into().unwrap()) as usize;
+        let changed = u32::from_le_bytes(delta[read + 4..read + 8].try_into().unwrap()) as usize;
+        read += 8;
+        position += unchanged;
+        state[position..position + changed].copy_from_slice(&delta[read..read + changed]);
+        read += changed;
+        position += changed;
+    }
+    state
+}
+
+pub fn hotkey_request(key: SDL_Keycode) -> Option<RewindRequest> {
+    if key == STEP_BACK_KEY {
+        Some(RewindRequest::StepBack)
+    } else if key == STEP_FORWARD_KEY {
+        Some(RewindRequest::StepForward)
+    } else if key == RESUME_KEY {
+        Some(RewindRequest::Resume)
+    } else {
+        None
+    }
+}
+
+pub fn request(rewind_request: RewindRequest) {
+    edit_global!(requests, REWIND_REQUESTS, {
+        requests.push(rewind_request);
+    });
+}
+
+// Call once per frame, right before the game updates.
+pub fn before_game_update(platform: &mut Platform) {
+    let mut requests = vec![];
+    edit_global!(rewind_requests, REWIND_REQUESTS, {
+        requests.append(&mut rewind_requests);
+    });
+    for rewind_request in requests {
+        if rewind_request != RewindRequest::Resume {
+            if recording::is_recording() {
+                log_warn!("rewind", "Can't rewind while recording");
+                continue;
+            }
+            // A looping recording would restore its own state over the rewind
+            platform.playback = None;
+        }
+        match rewind_request {
+            RewindRequest::StepBack => platform.rewind.step_back(),
+            RewindRequest::StepForward => platform.rewind.step_forward(),
+            RewindRequest::Seek(index) => platform.rewind.seek(index),
+            RewindRequest::Resume => {
+                if let Some(cursor) = platform.rewind.cursor {
+                    restore(&mut platform.memory, &platform.rewind.decode(cursor), false);
+                }
+                platform.rewind.resume();
+            }
+        }
+    }
+    match platform.rewind.cursor {
+        Some(cursor) => restore(&mut platform.memory, &platform.rewind.decode(cursor), true),
+        None => {
+            access_global!(game_input, GAME_INPUT, {
+                platform.rewind.pending_inputs = game_input.clone();
+            });
+        }
+    }
+    edit_global!(rewind_status, REWIND_STATUS, {
+        *rewind_status = platform.rewind.status();
+    });
+}
+
+// Call once per frame, right after the game updates, with whether
+// commands::freeze_if_paused froze it. A frozen frame is the one before it
+// again, storing it would only push older frames out of the budget.
+pub fn after_game_update(platform: &mut Platform, frozen: bool) {
+    if !platform.rewind.is_enabled() || platform.rewind.is_rewound() || frozen {
+        return;
+    }
+    let mut game_state_bytes = vec![];
+    access_global!(game_state, GAME_STATE, {
+        let game_state: &GameState = &game_state;
+        game_state_bytes = bincode::serialize(game_state).unwrap();
+    });
+    let arena = &platform.memory.permanent_arena;
+    let state = encode_state(
+        &game_state_bytes,
+        arena.used,
+        &bincode::serialize(&arena.tags).unwrap(),
+        memory::permanent_bytes(&platform.memory),
+    );
+    let inputs = std::mem::take(&mut platform.rewind.pending_inputs);
+    platform.rewind.push(state, inputs);
+}
+
+// A frame's state is the length of the serialized GAME_STATE, it, the
+// arena's used, the length of its serialized tags, them and then the arena,
+// the lengths as little endian u64s
+fn encode_state(game_state: &[u8], used: usize, tags: &[u8], arena: &[u8]) -> Vec<u8> {
+    let mut state = Vec::with_capacity(24 + game_state.len() + tags.len() + arena.len());
+    state.extend_from_slice(&(game_state.len() as u64).to_le_bytes());
+    state.extend_from_slice(game_state);
+    state.extend_from_slice(&(used as u64).to_le_bytes());
+    state.extend_from_slice(&(tags.len() as u64).to_le_bytes());
+    state.extend_from_slice(tags);
+    state.extend_from_slice(arena);
+    state
+}
+
+// The serialized GAME_STATE, the arena's used, its serialized tags and the
+// arena
+fn decode_state(state: &[u8]) -> (&[u8], usize, &[u8], &[u8]) {
+    let (length, rest) = state.split_at(8);
+    let (game_state, rest) = rest.split_at(u64::from_le_bytes(length.try_into().unwrap()) as usize);
+    let (used, rest) = rest.split_at(8);
+    let used = u64::from_le_bytes(used.try_into().unwrap()) as usize;
+    let (length, rest) = rest.split_at(8);
+    let (tags, arena) = rest.split_at(u64::from_le_bytes(length.try_into().unwrap()) as usize);
+    (game_state, used, tags, arena)
+}
+
+fn restore(memory: &mut GameMemory, state: &[u8], frozen: bool) {
+    let (game_state, used, tags, arena) = decode_state(state);
+    memory::restore_permanent(memory, arena, used, &bincode::deserialize(tags).unwrap());
+    let game_state: GameState = bincode::deserialize(game_state).unwrap();
+    edit_global!(current_game_state, GAME_STATE, {
+        *current_game_state = game_state;
+        if frozen {
+            current_game_state.timing_info.elapsed = 0.0;
+        }
+    });
+    edit_global!(game_input, GAME_INPUT, {
+        game_input.clear();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_round_trips() {
+        let previous: Vec<u8> = (0..100).collect();
+        let mut current = previous.clone();
+        current[0] = 200;
+        current[40..45].copy_from_slice(&[1, 2, 3, 4, 5]);
+        current[99] = 7;
+        let delta = encode_delta(&previous, &current);
+        assert!(delta.len() < current.len());
+        assert_eq!(apply_delta(&previous, &delta), current);
+    }
+
+    #[test]
+    fn delta_of_the_same_state_changes_nothing() {
+        let state = vec![9u8; 64];
+        let delta = encode_delta(&state, &state);
+        // A single run of everything unchanged
+        assert_eq!(delta.len(), 8);
+        assert_eq!(apply_delta(&state, &delta), state);
+    }
+
+    #[test]
+    fn delta_of_everything_changed() {
+        let previous = vec![0u8; 32];
+        let current = vec![1u8; 32];
+        assert_eq!(apply_delta(&previous, &encode_delta(&previous, &current)), current);
+    }
+
+    #[test]
+    fn state_encoding_round_trips() {
+        let state = encode_state(&[1, 2, 3], 2, &[8, 9], &[4, 5, 6, 7]);
+        assert_eq!(
+            decode_state(&state),
+            (&[1u8, 2, 3][..], 2, &[8u8, 9][..], &[4u8, 5, 6, 7][..])
+        );
+    }
+}
@@ -0,0 +1,307 @@
+// Emulator style save states.
+//
+// Ctrl+Shift+1..9 saves GAME_STATE into a slot, Ctrl+1..9 restores it. Each
+// slot is written next to the game library as <game>.slot<N>.sav, so slots
+// survive restarts, and is loaded again at startup. The editor manages the
+// same slots by pushing requests into SAVE_SLOT_REQUESTS. Nothing loads while
+// recording, the recording couldn't follow the jump.
+//
+// Next to the .sav each slot keeps a memory mapped <game>.slot<N>.mem with
+// the permanent arena of the game memory, which loading copies back in. The
+// tweak values go in the .sav with the state.
+//
+// Requests are handled after the game has updated and rendered, before the
+// frame is presented, so the thumbnail shows the frame the state belongs to.
+use anyhow::{bail, Context};
+use engine::{access_global, edit_global, GameState};
+use fermium::{
+    keyboard::SDL_Keysym,
+    keycode::{KMOD_LCTRL, KMOD_LSHIFT, KMOD_RCTRL, KMOD_RSHIFT, SDLK_1, SDLK_9},
+    pixels::SDL_PIXELFORMAT_ABGR8888,
+    prelude::{SDL_GetRendererOutputSize, SDL_RenderReadPixels},
+    renderer::SDL_Renderer,
+};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::frame_stats;
+use crate::memory::MemorySnapshot;
+use crate::recording;
+use crate::replay;
+use crate::tweaks::{self, TweakValues};
+use crate::{Platform, GAME_INPUT, GAME_STATE, SAVE_SLOTS, SAVE_SLOT_REQUESTS};
+
+pub const SLOT_COUNT: usize = 9;
+const THUMBNAIL_WIDTH: u32 = 160;
+const SAVE_STATE_MAGIC: [u8; 4] = *b"CMSS";
+// Bump this whenever SaveSlot changes shape
+const SAVE_STATE_FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct Thumbnail {
+    pub width: u32,
+    pub height: u32,
+    // Tightly packed RGBA, row by row from the top
+    pub rgba: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub struct SaveSlot {
+    // Milliseconds since the unix epoch
+    pub saved_at: u64,
+    pub game_library_hash: u64,
+    pub thumbnail: Thumbnail,
+    pub game_state: GameState,
+    pub tweaks: TweakValues,
+}
+
+// Slots are numbered 1 to SLOT_COUNT, like the keys that trigger them
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotRequest {
+    Save(usize),
+    Load(usize),
+    Delete(usize),
+}
+
+pub fn hotkey_request(keysym: &SDL_Keysym) -> Option<SlotRequest> {
+    let ctrl = keysym.mod_ & (KMOD_LCTRL.0 as u16 | KMOD_RCTRL.0 as u16) != 0;
+    let shift = keysym.mod_ & (KMOD_LSHIFT.0 as u16 | KMOD_RSHIFT.0 as u16) != 0;
+    if !ctrl || keysym.sym.0 < SDLK_1.0 || keysym.sym.0 > SDLK_9.0 {
+        return None;
+    }
+    let slot = (keysym.sym.0 - SDLK_1.0) as usize + 1;
+    if shift {
+        Some(SlotRequest::Save(slot))
+    } else {
+        Some(SlotRequest::Load(slot))
+    }
+}
+
+pub fn request(slot_request: SlotRequest) {
+    edit_global!(requests, SAVE_SLOT_REQUESTS, {
+        requests.push(slot_request);
+    });
+}
+
+// Call once per frame after the game has rendered and before presenting.
+pub fn handle_requests(platform: &mut Platform) {
+    let mut requests = vec![];
+    edit_global!(slot_requests, SAVE_SLOT_REQUESTS, {
+        requests.append(&mut slot_requests);
+    });
+    for slot_request in requests {
+        let result = match slot_request {
+            SlotRequest::Save(slot) => save(platform, slot),
+            SlotRequest::Load(slot) => load(platform, slot),
+            SlotRequest::Delete(slot) => delete(platform, slot),
+        };
+        if let Err(error) = result {
+            log_error!("save states", "Save state request {:?} failed: {:?}", slot_request, error);
+        }
+    }
+}
+
+// Reads every slot file that exists next to the game library into SAVE_SLOTS
+// and maps their memory snapshots
+pub fn load_all(platform: &mut Platform) {
+    for slot in 1..=SLOT_COUNT {
+        let memory_path = slot_path(platform, slot, "mem");
+        if memory_path.exists() {
+            match MemorySnapshot::open(&platform.memory, &memory_path) {
+                Ok(memory_snapshot) => platform.slot_memory[slot - 1] = Some(Arc::new(memory_snapshot)),
+                Err(error) => log_error!("save states", "Failed to map {}: {:?}", memory_path.display(), error),
+            }
+        }
+        let path = slot_path(platform, slot, "sav");
+        if !path.exists() {
+            continue;
+        }
+        match read_slot(&path) {
+            Ok(save_slot) => {
+                if save_slot.game_library_hash != platform.game_library_hash {
+                    log_warn!(
+                        "save states",
+                        "Save state slot {} was made with a different build of the game library",
+                        slot
+                    );
+                }
+                edit_global!(save_slots, SAVE_SLOTS, {
+                    save_slots[slot - 1] = Some(save_slot);
+                });
+            }
+            Err(error) => log_error!("save states", "Failed to read {}: {:?}", path.display(), error),
+        }
+    }
+}
+
+fn save(platform: &mut Platform, slot: usize) -> anyhow::Result<()> {
+    check_slot(slot)?;
+    // Unmap the old snapshot before overwriting its file
+    platform.slot_memory[slot - 1] = None;
+    let memory_snapshot = MemorySnapshot::capture(&platform.memory, Some(&slot_path(platform, slot, "mem")))?;
+    platform.slot_memory[slot - 1] = Some(Arc::new(memory_snapshot));
+    let mut game_state = GameState::default();
+    access_global!(current_game_state, GAME_STATE, {
+        game_state = current_game_state.clone();
+    });
+    let save_slot = SaveSlot {
+        saved_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_millis() as u64,
+        game_library_hash: platform.game_library_hash,
+        thumbnail: capture_thumbnail(platform.renderer),
+        game_state,
+        tweaks: tweaks::values(),
+    };
+    write_slot(&slot_path(platform, slot, "sav"), &save_slot)?;
+    edit_global!(save_slots, SAVE_SLOTS, {
+        save_slots[slot - 1] = Some(save_slot);
+    });
+    frame_stats::note("save state");
+    log_info!("save states", "Saved state to slot {}", slot);
+    Ok(())
+}
+
+fn load(platform: &mut Platform, slot: usize) -> anyhow::Result<()> {
+    check_slot(slot)?;
+    // The state would jump somewhere the recording never went
+    if recording::is_recording() {
+        bail!("can't load a state while recording");
+    }
+    let mut saved = None;
+    access_global!(save_slots, SAVE_SLOTS, {
+        saved = save_slots[slot - 1]
+            .as_ref()
+            .map(|save_slot| (save_slot.game_state.clone(), save_slot.tweaks.clone()));
+    });
+    let Some((game_state, saved_tweaks)) = saved else {
+        bail!("slot {} is empty", slot);
+    };
+    // A looping recording would restore its own state right over this one
+    platform.playback = None;
+    // So would rewind with the frame it's on
+    platform.rewind.stop_rewinding();
+    if let Some(memory_snapshot) = platform.slot_memory[slot - 1].as_ref() {
+        memory_snapshot.restore(&mut platform.memory);
+    }
+    tweaks::apply(&saved_tweaks);
+    edit_global!(current_game_state, GAME_STATE, {
+        *current_game_state = game_state;
+    });
+    edit_global!(game_input, GAME_INPUT, {
+        game_input.clear();
+    });
+    frame_stats::note("load state");
+    log_info!("save states", "Loaded state from slot {}", slot);
+    Ok(())
+}
+
+fn delete(platform: &mut Platform, slot: usize) -> anyhow::Result<()> {
+    check_slot(slot)?;
+    // Has to be unmapped before the file can go
+    platform.slot_memory[slot - 1] = None;
+    for extension in ["sav", "mem"] {
+        let path = slot_path(platform, slot, extension);
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("couldn't remove {}", path.display()))?;
+        }
+    }
+    edit_global!(save_slots, SAVE_SLOTS, {
+        save_slots[slot - 1] = None;
+    });
+    Ok(())
+}
+
+fn check_slot(slot: usize) -> anyhow::Result<()> {
+    if slot < 1 || slot > SLOT_COUNT {
+        bail!("there is no slot {}, slots go from 1 to {}", slot, SLOT_COUNT);
+    }
+    Ok(())
+}
+
+fn slot_path(platform: &Platform, slot: usize, extension: &str) -> PathBuf {
+    let game_dll = &platform.cli.game_dll;
+    let stem = game_dll
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "game".to_string());
+    game_dll.with_file_name(format!("{}.slot{}.{}", stem, slot, extension))
+}
+
+fn write_slot(path: &Path, save_slot: &SaveSlot) -> anyhow::Result<()> {
+    let file = File::create(path).with_context(|| format!("couldn't create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&SAVE_STATE_MAGIC)?;
+    writer.write_all(&SAVE_STATE_FORMAT_VERSION.to_le_bytes())?;
+    bincode::serialize_into(&mut writer, save_slot)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_slot(path: &Path) -> anyhow::Result<SaveSlot> {
+    let file = File::open(path)?;
+    let limit = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != SAVE_STATE_MAGIC {
+        bail!("not a save state file");
+    }
+    let mut format_version = [0u8; 4];
+    reader.read_exact(&mut format_version)?;
+    let format_version = u32::from_le_bytes(format_version);
+    if format_version != SAVE_STATE_FORMAT_VERSION {
+        bail!(
+            "save state format version {}, this build reads version {}",
+            format_version,
+            SAVE_STATE_FORMAT_VERSION
+        );
+    }
+    replay::deserialize_from(&mut reader, limit)
+}
+
+// Reads back what has been rendered so far this frame and scales it down to
+// THUMBNAIL_WIDTH. Returns an empty thumbnail if the renderer won't let us.
+fn capture_thumbnail(renderer: *mut SDL_Renderer) -> Thumbnail {
+    let mut width = 0;
+    let mut height = 0;
+    unsafe {
+        if SDL_GetRendererOutputSize(renderer, &mut width, &mut height) != 0 || width <= 0 || height <= 0 {
+            return Thumbnail::default();
+        }
+    }
+    let (width, height) = (width as u32, height as u32);
+    let mut pixels = vec![0u8; (width * height * 4) as usize];
+    unsafe {
+        // ABGR8888 is RGBA byte order on little endian
+        if SDL_RenderReadPixels(
+            renderer,
+            std::ptr::null(),
+            SDL_PIXELFORMAT_ABGR8888.0,
+            pixels.as_mut_ptr().cast(),
+            (width * 4) as i32,
+        ) != 0
+        {
+            return Thumbnail::default();
+        }
+    }
+    let thumbnail_width = THUMBNAIL_WIDTH.min(width);
+    let thumbnail_height = (height * thumbnail_width / width).max(1);
+    let mut rgba = Vec::with_capacity((thumbnail_width * thumbnail_height * 4) as usize);
+    for y in 0..thumbnail_height {
+        let source_y = y * height / thumbnail_height;
+        for x in 0..thumbnail_width {
+            let source_x = x * width / thumbnail_width;
+            let offset = ((source_y * width + source_x) * 4) as usize;
+            rgba.extend_from_slice(&pixels[offset..offset + 4]);
+        }
+    }
+    Thumbnail {
+        width: thumbnail_width,
+        height: thumbnail_height,
+        rgba,
+    }
+}
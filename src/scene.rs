@@ -0,0 +1,76 @@
+// Scenes, the entities, zmap and texts of GAME_STATE saved as a JSON file.
+//
+// This is how edits made in the editor become level data. The editor's Scene
+// panel saves and loads them, and --scene loads one at startup, right after
+// the game initializes. Each field is stored the way the game serializes it,
+// so a scene only loads into a game whose types still read it.
+//
+// A scene counts as unsaved when the undo history is at another edit than
+// when it was saved or loaded, so undoing back to it counts as saved again.
+// The game running on its own doesn't count.
+use anyhow::{anyhow, bail, Context};
+use engine::{access_global, edit_global, GameState};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::commands::set_from_json;
+use crate::entities;
+use crate::GAME_STATE;
+
+const SCENE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Scene {
+    version: u32,
+    entities: serde_json::Value,
+    zmap: serde_json::Value,
+    texts: serde_json::Value,
+}
+
+pub fn save(path: &Path) -> anyhow::Result<()> {
+    let mut scene = Err(anyhow!("couldn't read the game state"));
+    access_global!(game_state, GAME_STATE, {
+        let game_state: &GameState = &game_state;
+        scene = capture(game_state);
+    });
+    std::fs::write(path, serde_json::to_string_pretty(&scene?)?)
+        .with_context(|| format!("couldn't write {}", path.display()))?;
+    Ok(())
+}
+
+// Replaces the entities, zmap and texts, or leaves them all alone on failure.
+// Returns the remap for the game, every entity that was there is gone, see
+// entities.rs.
+pub fn load(path: &Path) -> anyhow::Result<Vec<usize>> {
+    let json = std::fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    let scene: Scene = serde_json::from_str(&json)?;
+    if scene.version != SCENE_VERSION {
+        bail!(
+            "{} is a version {} scene, this host reads version {}",
+            path.display(),
+            scene.version,
+            SCENE_VERSION
+        );
+    }
+    let mut result = Err(anyhow!("couldn't edit the game state"));
+    edit_global!(game_state, GAME_STATE, {
+        let mut loaded: GameState = game_state.clone();
+        result = set_from_json(&mut loaded.entities, &scene.entities)
+            .and_then(|()| set_from_json(&mut loaded.zmap, &scene.zmap))
+            .and_then(|()| set_from_json(&mut loaded.texts, &scene.texts))
+            .map(|()| vec![entities::REMOVED; game_state.entities.len()]);
+        if result.is_ok() {
+            *game_state = loaded;
+        }
+    });
+    result
+}
+
+fn capture(game_state: &GameState) -> anyhow::Result<Scene> {
+    Ok(Scene {
+        version: SCENE_VERSION,
+        entities: serde_json::to_value(&game_state.entities)?,
+        zmap: serde_json::to_value(&game_state.zmap)?,
+        texts: serde_json::to_value(&game_state.texts)?,
+    })
+}
@@ -0,0 +1,171 @@
+// The stats subcommand, lines of code per crate and language.
+//
+// Every root is counted as its own crate, named after the directory (or the
+// directory above a src directory). Unless --no-history is given, each run is
+// appended as one JSON line to the history file, and compared against the run
+// before it, so we can see the project grow.
+use clap::Args;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tokei::{Config, Languages};
+
+#[derive(Args, Debug, Clone)]
+pub struct StatsArgs {
+    // Directories to count, each one as a crate
+    #[arg(default_values = ["src", "../game/src", "../engine/src"])]
+    roots: Vec<PathBuf>,
+    // Print the stats as JSON instead of a table
+    #[arg(long)]
+    json: bool,
+    // Every run is appended to this file
+    #[arg(long, default_value = "project_stats_history.jsonl")]
+    history: PathBuf,
+    #[arg(long)]
+    no_history: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Counts {
+    pub code: usize,
+    pub comments: usize,
+    pub blanks: usize,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.code += other.code;
+        self.comments += other.comments;
+        self.blanks += other.blanks;
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrateStats {
+    pub name: String,
+    pub root: PathBuf,
+    pub languages: BTreeMap<String, Counts>,
+    pub total: Counts,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProjectStats {
+    // Seconds since the unix epoch
+    pub timestamp: u64,
+    pub crates: Vec<CrateStats>,
+    pub total: Counts,
+}
+
+pub fn run(args: &StatsArgs) -> anyhow::Result<()> {
+    let stats = count(&args.roots);
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        print_table(&stats);
+    }
+    if !args.no_history {
+        let previous = last_history_entry(&args.history);
+        append_history(&args.history, &stats)?;
+        // Keep the output parseable with --json
+        if let Some(previous) = previous.filter(|_| !args.json) {
+            println!(
+                "{:+} lines of code since the last run",
+                stats.total.code as i64 - previous.total.code as i64
+            );
+        }
+    }
+    Ok(())
+}
+
+fn count(roots: &[PathBuf]) -> ProjectStats {
+    let config = Config::default();
+    let mut crates = vec![];
+    let mut total = Counts::default();
+    for root in roots {
+        if !root.exists() {
+            // stdout is for the stats, it may be --json
+            eprintln!("Skipping {}, it doesn't exist", root.display());
+            continue;
+        }
+        let mut languages = Languages::new();
+        languages.get_statistics(&[root], &["target"], &config);
+        let mut crate_stats = CrateStats {
+            name: crate_name(root),
+            root: root.clone(),
+            languages: BTreeMap::new(),
+            total: Counts::default(),
+        };
+        for (language_type, language) in languages.iter() {
+            // Counts code embedded in other languages too, like code blocks
+            // in doc comments
+            let language = language.summarise();
+            if language.code + language.comments + language.blanks == 0 {
+                continue;
+            }
+            let counts = Counts {
+                code: language.code,
+                comments: language.comments,
+                blanks: language.blanks,
+            };
+            crate_stats.total.add(counts);
+            crate_stats.languages.insert(language_type.name().to_string(), counts);
+        }
+        total.add(crate_stats.total);
+        crates.push(crate_stats);
+    }
+    ProjectStats {
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs()),
+        crates,
+        total,
+    }
+}
+
+fn crate_name(root: &Path) -> String {
+    let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+    let directory = if root.file_name().map_or(false, |name| name == "src") {
+        root.parent().unwrap_or(root.as_path())
+    } else {
+        root.as_path()
+    };
+    directory
+        .file_name()
+        .map_or_else(|| root.display().to_string(), |name| name.to_string_lossy().into_owned())
+}
+
+fn print_table(stats: &ProjectStats) {
+    println!("{:<24} {:>10} {:>10} {:>10}", "", "code", "comments", "blanks");
+    for crate_stats in stats.crates.iter() {
+        print_row(&crate_stats.name, crate_stats.total);
+        for (language, counts) in crate_stats.languages.iter() {
+            print_row(&format!("  {}", language), *counts);
+        }
+    }
+    print_row("total", stats.total);
+}
+
+fn print_row(name: &str, counts: Counts) {
+    println!(
+        "{:<24} {:>10} {:>10} {:>10}",
+        name, counts.code, counts.comments, counts.blanks
+    );
+}
+
+fn last_history_entry(path: &Path) -> Option<ProjectStats> {
+    let file = std::fs::File::open(path).ok()?;
+    let last_line = BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .filter(|line| !line.trim().is_empty())
+        .last()?;
+    serde_json::from_str(&last_line).ok()
+}
+
+fn append_history(path: &Path, stats: &ProjectStats) -> anyhow::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(stats)?)?;
+    Ok(())
+}
@@ -0,0 +1,210 @@
+// Timelines in Chrome Trace Event JSON, for chrome://tracing or Perfetto.
+//
+// --trace <file> traces from startup, F11 (or the button in the profiler
+// panel) starts and stops a trace at any time, written to --trace or to
+// trace-<unix time>.json when there is none. Every profiler block becomes a
+// complete event on the thread it ran on, reloads and recordings show up as
+// instant markers, and threads are named with name_thread.
+//
+// Events are streamed to the file as they happen in the JSON array format,
+// which the viewers accept without the closing bracket, so a trace of a
+// session that crashed still loads, up to what was still buffered.
+use fermium::keycode::{SDL_Keycode, SDLK_F11};
+use serde_json::json;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::frame_stats;
+
+pub const TOGGLE_KEY: SDL_Keycode = SDLK_F11;
+
+struct Trace {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    start: Instant,
+    event_count: usize,
+}
+
+struct Tracer {
+    trace: Option<Trace>,
+    // Where toggle traces to, --trace
+    path: Option<PathBuf>,
+    // By trace thread id, so they can be written at the start of every trace
+    thread_names: BTreeMap<u32, String>,
+}
+
+lazy_static! {
+    static ref TRACER: Mutex<Tracer> = Mutex::new(Tracer {
+        trace: None,
+        path: None,
+        thread_names: BTreeMap::new(),
+    });
+}
+
+static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static THREAD_ID: Cell<u32> = Cell::new(0);
+}
+
+fn thread_id() -> u32 {
+    THREAD_ID.with(|id| {
+        if id.get() == 0 {
+            id.set(NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed));
+        }
+        id.get()
+    })
+}
+
+impl Trace {
+    fn write_event(&mut self, event: serde_json::Value) {
+        let separator = if self.event_count == 0 { "[\n" } else { ",\n" };
+        let result = write!(self.writer, "{}{}", separator, event);
+        if let Err(error) = result {
+            log_error!("trace", "Failed to write to {}: {:?}", self.path.display(), error);
+        }
+        self.event_count += 1;
+    }
+
+    fn timestamp(&self, instant: Instant) -> f64 {
+        // Microseconds, and 0 for anything from before the trace started
+        instant.saturating_duration_since(self.start).as_secs_f64() * 1_000_000.0
+    }
+}
+
+fn thread_name_event(thread_id: u32, name: &str) -> serde_json::Value {
+    json!({
+        "name": "thread_name",
+        "ph": "M",
+        "pid": std::process::id(),
+        "tid": thread_id,
+        "args": { "name": name },
+    })
+}
+
+pub fn is_tracing() -> bool {
+    TRACER.lock().unwrap().trace.is_some()
+}
+
+pub fn start(path: &Path) -> anyhow::Result<()> {
+    start_locked(&mut TRACER.lock().unwrap(), path)
+}
+
+// With TRACER already locked, so toggle can check and start in one go
+fn start_locked(tracer: &mut Tracer, path: &Path) -> anyhow::Result<()> {
+    let file = File::create(path)?;
+    let mut trace = Trace {
+        path: path.to_path_buf(),
+        writer: BufWriter::new(file),
+        start: Instant::now(),
+        event_count: 0,
+    };
+    trace.write_event(json!({
+        "name": "process_name",
+        "ph": "M",
+        "pid": std::process::id(),
+        "args": { "name": "win32_platform" },
+    }));
+    for (thread_id, name) in tracer.thread_names.iter() {
+        trace.write_event(thread_name_event(*thread_id, name));
+    }
+    log_info!("trace", "Tracing to {}", path.display());
+    tracer.trace = Some(trace);
+    Ok(())
+}
+
+pub fn stop() {
+    let trace = TRACER.lock().unwrap().trace.take();
+    if let Some(trace) = trace {
+        finish(trace);
+    }
+}
+
+// Once it's out of TRACER nothing else writes to it
+fn finish(mut trace: Trace) {
+    let result = trace.writer.write_all(b"\n]\n").and_then(|_| trace.writer.flush());
+    match result {
+        Ok(()) => log_info!("trace", "Wrote {} trace events to {}", trace.event_count, trace.path.display()),
+        Err(error) => log_error!("trace", "Failed to finish {}: {:?}", trace.path.display(), error),
+    }
+}
+
+pub fn set_path(path: Option<PathBuf>) {
+    TRACER.lock().unwrap().path = path;
+}
+
+// Starts a trace to the set_path path, or to trace-<unix time>.json without
+// one, or stops the running trace. Checks and switches under one lock, so two
+// toggles at once can't both start a trace.
+pub fn toggle() {
+    let mut tracer = TRACER.lock().unwrap();
+    if let Some(trace) = tracer.trace.take() {
+        drop(tracer);
+        finish(trace);
+        return;
+    }
+    let path = tracer.path.clone().unwrap_or_else(|| {
+        let seconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+        PathBuf::from(format!("trace-{}.json", seconds))
+    });
+    if let Err(error) = start_locked(&mut tracer, &path) {
+        log_error!("trace", "Failed to start tracing to {}: {:?}", path.display(), error);
+    }
+}
+
+// Names the calling thread in this and every later trace
+pub fn name_thread(name: &str) {
+    let thread_id = thread_id();
+    let mut tracer = TRACER.lock().unwrap();
+    tracer.thread_names.insert(thread_id, name.to_string());
+    if let Some(trace) = tracer.trace.as_mut() {
+        trace.write_event(thread_name_event(thread_id, name));
+    }
+}
+
+// Something that took from start to end on the calling thread
+pub fn complete_event(name: &str, start: Instant, end: Instant) {
+    let thread_id = thread_id();
+    let mut tracer = TRACER.lock().unwrap();
+    let Some(trace) = tracer.trace.as_mut() else {
+        return;
+    };
+    let timestamp = trace.timestamp(start);
+    let duration = end.saturating_duration_since(start).as_secs_f64() * 1_000_000.0;
+    trace.write_event(json!({
+        "name": name,
+        "ph": "X",
+        "pid": std::process::id(),
+        "tid": thread_id,
+        "ts": timestamp,
+        "dur": duration,
+    }));
+}
+
+// A line across every thread at this moment, for reloads and the like. It
+// goes into the hitch log too, traced or not.
+pub fn marker(name: &str) {
+    frame_stats::note(name);
+    let thread_id = thread_id();
+    let mut tracer = TRACER.lock().unwrap();
+    let Some(trace) = tracer.trace.as_mut() else {
+        return;
+    };
+    let timestamp = trace.timestamp(Instant::now());
+    trace.write_event(json!({
+        "name": name,
+        "ph": "i",
+        "s": "g",
+        "pid": std::process::id(),
+        "tid": thread_id,
+        "ts": timestamp,
+    }));
+}
@@ -0,0 +1,240 @@
+// Tweakable values for tuning the game while it runs.
+//
+// The game declares a tweakable through the TweakApi function table in
+// GameMemory by asking for its value, with a default (and a range for
+// numbers) for the first time it asks. A name like "player/jump height" puts
+// it in the "player" group. The editor shows every tweakable the game has
+// asked for and changes them with EditorCommand::SetTweak, so they go in the
+// undo history, and every finished edit is saved to the --tuning file, which
+// is loaded at startup so tuned values stick.
+//
+// Recordings and save states keep the values they were made with and put them
+// back when they play or load, so a replay runs with the tuning it was
+// recorded with. Like any edit, nothing can be tweaked while recording or
+// playing back.
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::ffi::{c_char, CStr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TweakColor {
+    pub r: f32,
+    pub g: f32,
+    pub b: f32,
+    pub a: f32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TweakApi {
+    // Each returns the current value of the named tweakable, declaring it on
+    // the first call. Names are C strings. Numbers take a default, min and max.
+    pub tweak_f32: unsafe extern "C" fn(*const c_char, f32, f32, f32) -> f32,
+    pub tweak_i32: unsafe extern "C" fn(*const c_char, i32, i32, i32) -> i32,
+    pub tweak_bool: unsafe extern "C" fn(*const c_char, bool) -> bool,
+    pub tweak_color: unsafe extern "C" fn(*const c_char, TweakColor) -> TweakColor,
+}
+
+impl TweakApi {
+    pub fn new() -> Self {
+        Self {
+            tweak_f32,
+            tweak_i32,
+            tweak_bool,
+            tweak_color,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TweakValue {
+    F32(f32),
+    I32(i32),
+    Bool(bool),
+    // RGBA, 0 to 1
+    Color([f32; 4]),
+}
+
+// By name
+pub type TweakValues = BTreeMap<String, TweakValue>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tweak {
+    pub value: TweakValue,
+    pub default: TweakValue,
+    // For F32 and I32, as f64 to fit both
+    pub min: f64,
+    pub max: f64,
+}
+
+struct Tweaks {
+    // Declared so far, by name
+    tweaks: BTreeMap<String, Tweak>,
+    // From the tuning file, for tweakables the game hasn't declared yet
+    tuned: TweakValues,
+    // Where to save, None until the tuning file loaded
+    path: Option<PathBuf>,
+}
+
+impl Tweaks {
+    // Every declared value, and the tuned ones the game hasn't declared this
+    // run so they aren't lost
+    fn values(&self) -> TweakValues {
+        let mut values = self.tuned.clone();
+        values.extend(self.tweaks.iter().map(|(name, tweak)| (name.clone(), tweak.value)));
+        values
+    }
+}
+
+lazy_static! {
+    static ref TWEAKS: Mutex<Tweaks> = Mutex::new(Tweaks {
+        tweaks: BTreeMap::new(),
+        tuned: BTreeMap::new(),
+        path: None,
+    });
+}
+
+// Call once at startup, before the game initializes. A missing file is fine,
+// it's written on the first edit. One that doesn't load is never written, so
+// fixing it by hand doesn't lose the values in it.
+pub fn load(path: &Path) -> anyhow::Result<()> {
+    let tuned = if path.exists() {
+        serde_json::from_str(&std::fs::read_to_string(path)?)?
+    } else {
+        TweakValues::new()
+    };
+    let mut tweaks = TWEAKS.lock().unwrap();
+    tweaks.tuned = tuned;
+    tweaks.path = Some(path.to_path_buf());
+    Ok(())
+}
+
+pub fn save() -> anyhow::Result<()> {
+    let tweaks = TWEAKS.lock().unwrap();
+    let Some(path) = tweaks.path.as_ref() else {
+        return Ok(());
+    };
+    std::fs::write(path, serde_json::to_string_pretty(&tweaks.values())?)?;
+    Ok(())
+}
+
+// For recordings and save states
+pub fn values() -> TweakValues {
+    TWEAKS.lock().unwrap().values()
+}
+
+// Puts back what values returned, without saving it to the tuning file
+pub fn apply(values: &TweakValues) {
+    let mut tweaks = TWEAKS.lock().unwrap();
+    for (name, value) in values {
+        match tweaks.tweaks.get_mut(name) {
+            Some(tweak) => {
+                if let Some(value) = fit(*value, tweak) {
+                    tweak.value = value;
+                }
+            }
+            None => {
+                tweaks.tuned.insert(name.clone(), *value);
+            }
+        }
+    }
+}
+
+pub fn all() -> BTreeMap<String, Tweak> {
+    TWEAKS.lock().unwrap().tweaks.clone()
+}
+
+// Clamped to the tweakable's range. Returns the value it had and the one it
+// has now. Call from commands.rs, between frames.
+pub fn set(name: &str, value: TweakValue) -> anyhow::Result<(TweakValue, TweakValue)> {
+    let mut tweaks = TWEAKS.lock().unwrap();
+    let Some(tweak) = tweaks.tweaks.get_mut(name) else {
+        bail!("there is no tweakable {}", name);
+    };
+    let Some(value) = fit(value, tweak) else {
+        bail!("{} can't be set to {:?}", name, value);
+    };
+    let before = tweak.value;
+    tweak.value = value;
+    Ok((before, value))
+}
+
+// The value converted to the kind of the tweakable and clamped to its range,
+// or None when it can't be
+fn fit(value: TweakValue, tweak: &Tweak) -> Option<TweakValue> {
+    match (tweak.default, value) {
+        (TweakValue::F32(_), TweakValue::F32(value)) => {
+            Some(TweakValue::F32(value.clamp(tweak.min as f32, tweak.max as f32)))
+        }
+        (TweakValue::F32(_), TweakValue::I32(value)) => {
+            Some(TweakValue::F32((value as f32).clamp(tweak.min as f32, tweak.max as f32)))
+        }
+        (TweakValue::I32(_), TweakValue::I32(value)) => {
+            Some(TweakValue::I32(value.clamp(tweak.min as i32, tweak.max as i32)))
+        }
+        (TweakValue::Bool(_), TweakValue::Bool(value)) => Some(TweakValue::Bool(value)),
+        (TweakValue::Color(_), TweakValue::Color(value)) => Some(TweakValue::Color(value)),
+        _ => None,
+    }
+}
+
+// The current value, declaring the tweakable with default the first time
+fn declare(name: *const c_char, default: TweakValue, min: f64, max: f64) -> TweakValue {
+    if name.is_null() {
+        return default;
+    }
+    let name = unsafe { CStr::from_ptr(name) }.to_string_lossy();
+    let mut tweaks = TWEAKS.lock().unwrap();
+    if let Some(tweak) = tweaks.tweaks.get(name.as_ref()) {
+        // Asked for as another kind, give the game what it expects
+        if std::mem::discriminant(&tweak.value) != std::mem::discriminant(&default) {
+            return default;
+        }
+        return tweak.value;
+    }
+    let mut tweak = Tweak {
+        value: default,
+        default,
+        min: min.min(max),
+        max: max.max(min),
+    };
+    if let Some(tuned) = tweaks.tuned.remove(name.as_ref()) {
+        tweak.value = fit(tuned, &tweak).unwrap_or(default);
+    }
+    let value = tweak.value;
+    tweaks.tweaks.insert(name.into_owned(), tweak);
+    value
+}
+
+unsafe extern "C" fn tweak_f32(name: *const c_char, default: f32, min: f32, max: f32) -> f32 {
+    match declare(name, TweakValue::F32(default), min as f64, max as f64) {
+        TweakValue::F32(value) => value,
+        _ => default,
+    }
+}
+
+unsafe extern "C" fn tweak_i32(name: *const c_char, default: i32, min: i32, max: i32) -> i32 {
+    match declare(name, TweakValue::I32(default), min as f64, max as f64) {
+        TweakValue::I32(value) => value,
+        _ => default,
+    }
+}
+
+unsafe extern "C" fn tweak_bool(name: *const c_char, default: bool) -> bool {
+    match declare(name, TweakValue::Bool(default), 0.0, 0.0) {
+        TweakValue::Bool(value) => value,
+        _ => default,
+    }
+}
+
+unsafe extern "C" fn tweak_color(name: *const c_char, default: TweakColor) -> TweakColor {
+    let rgba = [default.r, default.g, default.b, default.a];
+    match declare(name, TweakValue::Color(rgba), 0.0, 0.0) {
+        TweakValue::Color([r, g, b, a]) => TweakColor { r, g, b, a },
+        _ => default,
+    }
+}
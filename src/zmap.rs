@@ -0,0 +1,277 @@
+// The z-map, which layer every entity is drawn on.
+//
+// GameState.zmap maps a z-layer to its members and the game draws the layers
+// in order. The host only looks at it through serde, as a JSON object of
+// layer to an array of members, so it doesn't care what types the game uses
+// for either. Layer keys are whatever the key serializes to as a JSON object
+// key.
+//
+// Hiding a layer is up to the game. It asks through the LayerApi function
+// table in GameMemory whether to draw a layer, and skips drawing it but
+// updates everything on it as usual, so hiding layers never changes what the
+// game does, and a recording plays back the same with or without them.
+use anyhow::bail;
+use engine::GameState;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::ffi::{c_char, CStr};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub key: String,
+    pub members: Vec<Value>,
+}
+
+// Where a member is, what undoing a move puts it back to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MemberPosition {
+    pub layer: String,
+    pub index: usize,
+}
+
+// Which layers the game gets to draw, kept by the host, never saved
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayerVisibility {
+    pub hidden: BTreeSet<String>,
+    // Only this layer is drawn, hidden or not
+    pub solo: Option<String>,
+}
+
+impl LayerVisibility {
+    pub fn is_visible(&self, layer: &str) -> bool {
+        match self.solo.as_ref() {
+            Some(solo) => solo == layer,
+            None => !self.hidden.contains(layer),
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct LayerApi {
+    // Whether to draw a layer, by its key as a C string the way it serializes
+    // as a JSON object key, so layer 3 is "3". A null key is visible.
+    pub is_layer_visible: unsafe extern "C" fn(*const c_char) -> bool,
+}
+
+impl LayerApi {
+    pub fn new() -> Self {
+        Self { is_layer_visible }
+    }
+}
+
+lazy_static! {
+    // What the LayerApi answers with, a copy of the platform's
+    static ref VISIBILITY: Mutex<LayerVisibility> = Mutex::new(LayerVisibility::default());
+}
+
+// Call whenever the platform's layer visibility changes
+pub fn set_visibility(visibility: &LayerVisibility) {
+    *VISIBILITY.lock().unwrap() = visibility.clone();
+}
+
+// In draw order, or an error when the zmap isn't a map of arrays
+pub fn layers(game_state: &GameState) -> anyhow::Result<Vec<Layer>> {
+    let Value::Object(layers) = serde_json::to_value(&game_state.zmap)? else {
+        bail!("the zmap doesn't serialize to a map");
+    };
+    let mut result = vec![];
+    for (key, members) in layers {
+        let Value::Array(members) = members else {
+            bail!("layer {} isn't a list of members", key);
+        };
+        result.push(Layer { key, members });
+    }
+    result.sort_by(|a, b| compare_keys(&a.key, &b.key));
+    Ok(result)
+}
+
+// JSON object keys are strings, so a zmap with integer keys comes out of serde
+// as "10" before "2". Keys that are numbers go in number order, before any
+// that aren't.
+fn compare_keys(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(a), Ok(b)) => a.total_cmp(&b),
+        (Ok(_), Err(_)) => Ordering::Less,
+        (Err(_), Ok(_)) => Ordering::Greater,
+        (Err(_), Err(_)) => a.cmp(b),
+    }
+}
+
+// Edits the zmap as a JSON object of layer to members, and writes it back
+pub fn edit_layers<T: Serialize + DeserializeOwned, R>(
+    zmap: &mut T,
+    edit: impl FnOnce(&mut Map<String, Value>) -> anyhow::Result<R>,
+) -> anyhow::Result<R> {
+    let mut value = serde_json::to_value(&*zmap)?;
+    let Some(layers) = value.as_object_mut() else {
+        bail!("the zmap doesn't serialize to a map");
+    };
+    let edited = edit(layers)?;
+    *zmap = serde_json::from_value(value)?;
+    Ok(edited)
+}
+
+pub fn find_member(layers: &Map<String, Value>, member: &Value) -> anyhow::Result<MemberPosition> {
+    for (key, members) in layers {
+        let Some(members) = members.as_array() else {
+            bail!("layer {} isn't a list of members", key);
+        };
+        if let Some(index) = members.iter().position(|other| other == member) {
+            return Ok(MemberPosition {
+                layer: key.clone(),
+                index,
+            });
+        }
+    }
+    bail!("{} isn't on any layer", member);
+}
+
+// Puts member exactly at position, as find_member gave it
+pub fn place_member(layers: &mut Map<String, Value>, member: &Value, position: &MemberPosition) -> anyhow::Result<()> {
+    let current = find_member(layers, member)?;
+    // move_member counts the member itself when it goes further down its layer
+    let index = if current.layer == position.layer && current.index < position.index {
+        position.index + 1
+    } else {
+        position.index
+    };
+    move_member(layers, member, &position.layer, Some(index))
+}
+
+// Takes member out of whatever layer it's on and puts it on layer, before the
+// member at index or at the end
+pub fn move_member(
+    layers: &mut Map<String, Value>,
+    member: &Value,
+    layer: &str,
+    index: Option<usize>,
+) -> anyhow::Result<()> {
+    if !layers.contains_key(layer) {
+        bail!("there is no layer {}", layer);
+    }
+    let mut index = index;
+    let mut found = false;
+    for (key, members) in layers.iter_mut() {
+        let Some(members) = members.as_array_mut() else {
+            bail!("layer {} isn't a list of members", key);
+        };
+        if let Some(position) = members.iter().position(|other| other == member) {
+            members.remove(position);
+            found = true;
+            // Moving down within a layer shifts where it goes
+            if key == layer {
+                index = index.map(|index| if index > position { index - 1 } else { index });
+            }
+        }
+    }
+    if !found {
+        bail!("{} isn't on any layer", member);
+    }
+    let members = layers[layer].as_array_mut().unwrap();
+    let index = index.unwrap_or(members.len()).min(members.len());
+    members.insert(index, member.clone());
+    Ok(())
+}
+
+unsafe extern "C" fn is_layer_visible(key: *const c_char) -> bool {
+    if key.is_null() {
+        return true;
+    }
+    let key = CStr::from_ptr(key).to_string_lossy();
+    VISIBILITY.lock().unwrap().is_visible(&key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn as_layers(zmap: Value) -> Map<String, Value> {
+        let Value::Object(layers) = zmap else {
+            panic!("not a map");
+        };
+        layers
+    }
+
+    #[test]
+    fn moves_a_member_to_another_layer() {
+        let mut zmap = as_layers(json!({ "0": [0, 1], "1": [2] }));
+        move_member(&mut zmap, &json!(1), "1", Some(0)).unwrap();
+        assert_eq!(Value::Object(zmap), json!({ "0": [0], "1": [1, 2] }));
+    }
+
+    #[test]
+    fn moves_a_member_to_the_end() {
+        let mut zmap = as_layers(json!({ "0": [0], "1": [1] }));
+        move_member(&mut zmap, &json!(0), "1", None).unwrap();
+        assert_eq!(Value::Object(zmap), json!({ "0": [], "1": [1, 0] }));
+    }
+
+    #[test]
+    fn moves_a_member_down_within_its_layer() {
+        let mut zmap = as_layers(json!({ "0": [0, 1, 2] }));
+        // Before 2, which is at 1 once 0 is taken out
+        move_member(&mut zmap, &json!(0), "0", Some(2)).unwrap();
+        assert_eq!(Value::Object(zmap), json!({ "0": [1, 0, 2] }));
+    }
+
+    #[test]
+    fn moves_a_member_up_within_its_layer() {
+        let mut zmap = as_layers(json!({ "0": [0, 1, 2] }));
+        move_member(&mut zmap, &json!(2), "0", Some(0)).unwrap();
+        assert_eq!(Value::Object(zmap), json!({ "0": [2, 0, 1] }));
+    }
+
+    #[test]
+    fn refuses_unknown_layers_and_members() {
+        let mut zmap = as_layers(json!({ "0": [0] }));
+        assert!(move_member(&mut zmap, &json!(0), "1", None).is_err());
+        assert!(move_member(&mut zmap, &json!(7), "0", None).is_err());
+        assert_eq!(Value::Object(zmap), json!({ "0": [0] }));
+    }
+
+    #[test]
+    fn places_a_member_back_where_it_was() {
+        let mut zmap = as_layers(json!({ "0": [0, 1, 2], "1": [3] }));
+        let before = find_member(&zmap, &json!(0)).unwrap();
+        assert_eq!(
+            before,
+            MemberPosition {
+                layer: "0".to_string(),
+                index: 0
+            }
+        );
+        move_member(&mut zmap, &json!(0), "1", None).unwrap();
+        place_member(&mut zmap, &json!(0), &before).unwrap();
+        assert_eq!(Value::Object(zmap.clone()), json!({ "0": [0, 1, 2], "1": [3] }));
+        let before = find_member(&zmap, &json!(1)).unwrap();
+        move_member(&mut zmap, &json!(1), "0", Some(0)).unwrap();
+        let after = find_member(&zmap, &json!(1)).unwrap();
+        place_member(&mut zmap, &json!(1), &before).unwrap();
+        assert_eq!(Value::Object(zmap.clone()), json!({ "0": [0, 1, 2], "1": [3] }));
+        place_member(&mut zmap, &json!(1), &after).unwrap();
+        assert_eq!(Value::Object(zmap), json!({ "0": [1, 0, 2], "1": [3] }));
+    }
+
+    #[test]
+    fn solo_wins_over_hidden() {
+        let mut visibility = LayerVisibility::default();
+        visibility.hidden.insert("1".to_string());
+        assert!(visibility.is_visible("0"));
+        assert!(!visibility.is_visible("1"));
+        visibility.solo = Some("1".to_string());
+        assert!(!visibility.is_visible("0"));
+        assert!(visibility.is_visible("1"));
+    }
+
+    #[test]
+    fn number_keys_order_by_number() {
+        let mut keys = ["a", "10", "2", "-1"];
+        keys.sort_by(|a, b| compare_keys(a, b));
+        assert_eq!(keys, ["-1", "2", "10", "a"]);
+    }
+}